@@ -18,8 +18,9 @@ use crate::{
             flags::{ACCESSED, DIRTY, EXECUTE, READ, VALID, WRITE},
             PageSize, PageTable, PhysicalAddress, VirtualAddress, SATP_MODE,
         },
-        phys::{PhysicalMemoryAllocator, PHYSICAL_MEMORY_ALLOCATOR},
+        phys::{self, PhysicalMemoryAllocator, PhysicalPage, MAX_MEMORY_REGIONS, PHYSICAL_MEMORY_ALLOCATOR},
     },
+    platform::{self, ExitStatus},
     utils::{LinkerSymbol, Units},
 };
 
@@ -45,9 +46,22 @@ pub static BOOTSTRAP_SATP: AtomicUsize = AtomicUsize::new(0);
 pub unsafe extern "C" fn early_paging(hart_id: usize, fdt: *const u8, phys_load: usize) -> ! {
     let fdt_struct: Fdt<'static> = match fdt::Fdt::from_ptr(fdt) {
         Ok(fdt) => fdt,
-        Err(e) => crate::platform::exit(crate::platform::ExitStatus::Error(&e)),
+        Err(e) => platform::exit(ExitStatus::Error(&e)),
     };
 
+    const EMPTY_REGION: core::ops::Range<usize> = 0..0;
+    let mut regions = [EMPTY_REGION; MAX_MEMORY_REGIONS];
+    let mut n_regions = 0;
+    for (slot, region) in regions.iter_mut().zip(fdt_struct.memory().regions()) {
+        let start = region.starting_address as usize;
+        *slot = start..start + region.size.unwrap_or(0);
+        n_regions += 1;
+    }
+
+    if let Some((a, b)) = phys::find_overlapping_regions(&mut regions[..n_regions]) {
+        platform::exit(ExitStatus::Error(&format_args!("memory regions {:#x?} and {:#x?} overlap", a, b)));
+    }
+
     let fdt_size = fdt_struct.total_size() as u64;
 
     let page_offset_value = kernel_patching::page_offset();
@@ -74,10 +88,20 @@ pub unsafe extern "C" fn early_paging(hart_id: usize, fdt: *const u8, phys_load:
     let mut pf_alloc = PHYSICAL_MEMORY_ALLOCATOR.lock();
     pf_alloc.init(kernel_end_phys, (start + size) as *mut u8);
 
+    // Firmware can reserve memory for itself, which must never be handed out
+    for reservation in fdt_struct.memory_reservations() {
+        let reserved_start = (reservation.address() as usize & !(4.kib() - 1)).max(kernel_end);
+        let reserved_end = (reservation.address() as usize + reservation.size()).min(start + size);
+
+        for page in (reserved_start..reserved_end).step_by(4.kib()) {
+            pf_alloc.set_used(PhysicalPage::from_ptr(page as *mut u8));
+        }
+    }
+
     if fdt > kernel_end_phys {
         let n_pages = fdt_size as usize / 4096 + 1;
         for i in 0..n_pages {
-            pf_alloc.set_used(crate::mem::phys::PhysicalPage::from_ptr(fdt.add(i * 4096) as *mut _));
+            pf_alloc.set_used(PhysicalPage::from_ptr(fdt.add(i * 4096) as *mut _));
         }
     }
 
@@ -6,7 +6,11 @@
 // obtain one at https://mozilla.org/MPL/2.0/.
 
 use alloc::collections::BTreeMap;
-use librust::{capabilities::CapabilityPtr, syscalls::channel::ChannelId};
+use librust::{
+    capabilities::CapabilityPtr,
+    syscalls::{channel::ChannelId, endpoint::EndpointId},
+    task::Tid,
+};
 
 pub struct CapabilitySpace {
     inner: BTreeMap<CapabilityPtr, Capability>,
@@ -34,6 +38,34 @@ impl CapabilitySpace {
     pub fn resolve_mut(&mut self, cptr: CapabilityPtr) -> Option<&mut Capability> {
         self.inner.get_mut(&cptr)
     }
+
+    /// Remove the capability pointed to by `cptr`, for when the resource it
+    /// refers to goes away
+    pub fn revoke(&mut self, cptr: CapabilityPtr) -> Option<Capability> {
+        self.inner.remove(&cptr)
+    }
+
+    /// Whether the capability pointed to by `cptr` refers to the given
+    /// [`ChannelId`] and has at least the requested [`CapabilityRights`]
+    pub fn grants_channel(&self, cptr: CapabilityPtr, channel: ChannelId, rights: CapabilityRights) -> bool {
+        match self.resolve(cptr) {
+            Some(Capability { resource: CapabilityResource::Channel(id), rights: has }) => {
+                *id == channel && *has & rights
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the space holds a capability to `owner`'s endpoint `endpoint`
+    /// with at least the requested [`CapabilityRights`]
+    pub fn grants_endpoint(&self, owner: Tid, endpoint: EndpointId, rights: CapabilityRights) -> bool {
+        self.inner.values().any(|capability| match capability {
+            Capability { resource: CapabilityResource::Endpoint(cap_owner, id), rights: has } => {
+                *cap_owner == owner && *id == endpoint && *has & rights
+            }
+            _ => false,
+        })
+    }
 }
 
 pub struct Capability {
@@ -43,11 +75,14 @@ pub struct Capability {
 
 pub enum CapabilityResource {
     Channel(ChannelId),
+    /// An endpoint and the task which owns it
+    Endpoint(Tid, EndpointId),
     Grant,
     Mint,
     Revoke,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(transparent)]
 pub struct CapabilityRights(u8);
 
@@ -37,6 +37,12 @@ pub mod sip {
 
         val
     }
+
+    /// Clear a pending supervisor software interrupt (e.g. an IPI)
+    #[inline(always)]
+    pub fn clear_ssip() {
+        unsafe { asm!("csrci sip, 2") };
+    }
 }
 
 pub mod sstatus {
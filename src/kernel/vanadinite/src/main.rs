@@ -76,7 +76,11 @@ use sbi::{hart_state_management::hart_start, probe_extension, ExtensionAvailabil
 use scheduler::Scheduler;
 pub use vanadinite_macros::{debug, error, info, trace, warn};
 
+/// One more than the highest hart ID in the system, used to size arrays
+/// indexed by hart ID
 static N_CPUS: AtomicUsize = AtomicUsize::new(1);
+/// The number of harts which have booted and can run tasks
+static ONLINE_HARTS: AtomicUsize = AtomicUsize::new(1);
 static TIMER_FREQ: AtomicU64 = AtomicU64::new(0);
 static INIT: &[u8] = include_bytes!("../../../../build/init");
 
@@ -91,6 +95,7 @@ extern "C" fn kmain(hart_id: usize, fdt: *const u8) -> ! {
 
     unsafe { cpu_local::init_thread_locals() };
     HART_ID.set(hart_id);
+    sync::set_hart_id_source(|| HART_ID.get());
 
     io::logging::init_logging();
 
@@ -140,6 +145,14 @@ extern "C" fn kmain(hart_id: usize, fdt: *const u8) -> ! {
                     None => log::warn!("No path provided for init process! Defaulting to `init`"),
                 },
                 "no-color" | "no-colour" => io::logging::USE_COLOR.store(false, Ordering::Relaxed),
+                "time-slice" => match scheduler::parse_time_slice(value) {
+                    Some(us) => scheduler::TIME_SLICE_US.store(us, Ordering::Relaxed),
+                    _ => log::warn!("Invalid time slice `{:?}`, expected a non-zero number of microseconds", value),
+                },
+                "channel-limit" => match value.map(str::parse::<usize>) {
+                    Some(Ok(limit)) => task::DEFAULT_CHANNEL_LIMIT.store(limit, Ordering::Relaxed),
+                    _ => log::warn!("Invalid channel limit `{:?}`, expected a number of channels", value),
+                },
                 "console" => match value {
                     Some("sbi") => {
                         if let ExtensionAvailability::Available(_) = probe_extension(sbi::legacy::CONSOLE_PUTCHAR_EID) {
@@ -208,7 +221,10 @@ extern "C" fn kmain(hart_id: usize, fdt: *const u8) -> ! {
     };
 
     let n_cpus = fdt.cpus().count();
-    N_CPUS.store(n_cpus, Ordering::Release);
+    // Hart IDs don't have to be contiguous, so anything indexed by them needs
+    // room for the highest one rather than one per hart
+    let max_hart_id = fdt.cpus().map(|cpu| cpu.ids().first()).max().unwrap_or(0);
+    N_CPUS.store(max_hart_id + 1, Ordering::Release);
 
     info!("vanadinite version {#brightgreen}", env!("CARGO_PKG_VERSION"));
     info!(blue, "=== Machine Info ===");
@@ -314,6 +330,7 @@ extern "C" fn kalt(hart_id: usize) -> ! {
     HART_ID.set(hart_id);
 
     info!(brightgreen, "Hart {} successfully booted", HART_ID.get());
+    ONLINE_HARTS.fetch_add(1, Ordering::AcqRel);
 
     if let Some(plic) = &*PLIC.lock() {
         plic.set_context_threshold(platform::current_plic_context(), 0);
@@ -33,6 +33,7 @@ impl AddressRegion {
 /// Describes what type of memory the address region contains
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AddressRegionKind {
+    Borrowed,
     Channel,
     Data,
     Guard,
@@ -183,6 +184,12 @@ impl AddressMap {
         self.map.range(address..).next().map(|(_, r)| r)
     }
 
+    /// Find the region containing the given [`VirtualAddress`], allowing its
+    /// backing to be modified in place
+    pub fn find_mut(&mut self, address: VirtualAddress) -> Option<&mut AddressRegion> {
+        self.map.range_mut(address..).next().map(|(_, r)| r)
+    }
+
     /// Returns the unoccupied regions in the address space
     pub fn unoccupied_regions(&self) -> impl Iterator<Item = &AddressRegion> {
         self.map.values().filter(|v| v.region.is_none())
@@ -20,6 +20,7 @@ use crate::{
 };
 use address_map::AddressMap;
 pub use address_map::{AddressRegion, AddressRegionKind};
+use alloc::vec::Vec;
 use core::ops::Range;
 
 use super::region::SharedPhysicalRegion;
@@ -28,6 +29,9 @@ pub enum FillOption<'a> {
     Data(&'a [u8]),
     Unitialized,
     Zeroed,
+    /// Every byte set to the given value, e.g. to make memory that shouldn't
+    /// be read stand out when it is
+    Pattern(u8),
 }
 
 pub enum InvalidRegion {
@@ -79,11 +83,7 @@ impl MemoryManager {
             UniquePhysicalRegion::alloc_sparse(size, len)
         };
 
-        match fill {
-            FillOption::Data(data) => backing.copy_data_into(data),
-            FillOption::Zeroed => backing.zero(),
-            FillOption::Unitialized => {}
-        }
+        backing.fill(fill);
 
         let iter = backing.physical_addresses().enumerate().map(|(i, phys)| (phys, at.add(i * size.to_byte_size())));
         for (phys_addr, virt_addr) in iter {
@@ -116,13 +116,15 @@ impl MemoryManager {
 
     /// Same as [`Self::alloc_region`] except produces a
     /// [`crate::mem::region::SharedPhysicalRegion`] which can be cheaply shared
-    /// between tasks
+    /// between tasks. [`PageSize::Gigapage`] regions are transparently backed
+    /// by megapages if the requested address isn't gigapage aligned.
     pub fn alloc_shared_region(
         &mut self,
         at: Option<VirtualAddress>,
         description: RegionDescription,
     ) -> (Range<VirtualAddress>, SharedPhysicalRegion) {
         let RegionDescription { size, len, contiguous, flags, fill, kind } = description;
+        let (size, len, contiguous) = shared_region_layout(at, size, len, contiguous);
         let at = at.unwrap_or_else(|| self.find_free_region(size, len));
         let mut backing = if contiguous {
             UniquePhysicalRegion::alloc_contiguous(size, len)
@@ -130,17 +132,8 @@ impl MemoryManager {
             UniquePhysicalRegion::alloc_sparse(size, len)
         };
 
-        match fill {
-            FillOption::Data(data) => backing.copy_data_into(data),
-            FillOption::Zeroed => backing.zero(),
-            FillOption::Unitialized => {}
-        }
-
-        let iter = backing.physical_addresses().enumerate().map(|(i, phys)| (phys, at.add(i * size.to_byte_size())));
-        for (phys_addr, virt_addr) in iter {
-            self.table.map(phys_addr, virt_addr, flags, size);
-            sfence(Some(virt_addr), None);
-        }
+        backing.fill(fill);
+        self.map_pages(at, backing.physical_addresses(), flags, size);
 
         let shared = backing.into_shared_region();
         let range = at..at.add(size.to_byte_size() * len);
@@ -152,30 +145,98 @@ impl MemoryManager {
         (range, shared)
     }
 
-    pub fn apply_shared_region(
+    /// Reserve a region of `len` pages of the given [`PageSize`] without
+    /// backing any of it with physical memory yet. Each page is allocated,
+    /// zeroed, and mapped with `flags` by [`Self::fill_lazy_page`] once it's
+    /// first touched.
+    pub fn alloc_lazy_region(
         &mut self,
         at: Option<VirtualAddress>,
+        size: PageSize,
+        len: usize,
         flags: Flags,
-        region: SharedPhysicalRegion,
         kind: AddressRegionKind,
     ) -> Range<VirtualAddress> {
-        let at = at.unwrap_or_else(|| self.find_free_region(region.page_size(), region.n_pages()));
+        let at = at.unwrap_or_else(|| self.find_free_region(size, len));
 
-        let iter = region
-            .physical_addresses()
-            .enumerate()
-            .map(|(i, phys)| (phys, at.add(i * region.page_size().to_byte_size())));
+        log::debug!("Allocating lazy region at {:#p}: size={:?} n_pages={} flags={:?}", at, size, len, flags);
 
-        for (phys_addr, virt_addr) in iter {
-            self.table.map(phys_addr, virt_addr, flags, region.page_size());
-            sfence(Some(virt_addr), None);
+        let range = at..at.add(size.to_byte_size() * len);
+        let region = MemoryRegion::Lazy { page_size: size, n_pages: len, flags, backing: Vec::new() };
+        self.address_map.alloc(range.clone(), region, kind).expect("bad address mapping");
+
+        range
+    }
+
+    /// Back the page containing `at` with zeroed memory if it's part of a lazy
+    /// region and hasn't been touched before, returning whether it was
+    pub fn fill_lazy_page(&mut self, at: VirtualAddress) -> bool {
+        let (page_size, flags) = match self.address_map.find(at) {
+            Some(AddressRegion { region: Some(MemoryRegion::Lazy { page_size, flags, .. }), .. }) => {
+                (*page_size, *flags)
+            }
+            _ => return false,
+        };
+
+        let page = at.align_down_to(page_size);
+        if self.table.page_flags(page).is_some() {
+            return false;
         }
 
+        let mut backing = UniquePhysicalRegion::alloc_contiguous(page_size, 1);
+        backing.zero();
+        self.map_pages(page, backing.physical_addresses(), flags, page_size);
+
+        match self.address_map.find_mut(at) {
+            Some(AddressRegion { region: Some(MemoryRegion::Lazy { backing: pages, .. }), .. }) => pages.push(backing),
+            _ => unreachable!(),
+        }
+
+        true
+    }
+
+    /// Map an existing [`SharedPhysicalRegion`] into this address space,
+    /// handing the region back if there's no room for it, either because `at`
+    /// is already occupied or because the address space has been exhausted
+    pub fn apply_shared_region(
+        &mut self,
+        at: Option<VirtualAddress>,
+        flags: Flags,
+        region: SharedPhysicalRegion,
+        kind: AddressRegionKind,
+    ) -> Result<Range<VirtualAddress>, SharedPhysicalRegion> {
+        let at = match at.or_else(|| self.try_find_free_region(region.page_size(), region.n_pages())) {
+            Some(at) => at,
+            None => return Err(region),
+        };
+
         let range = at..at.add(region.page_size().to_byte_size() * region.n_pages());
 
-        self.address_map.alloc(range.clone(), MemoryRegion::Backed(PhysicalRegion::Shared(region)), kind).unwrap();
+        // Claim the range before mapping anything so there's nothing to undo
+        let backing = MemoryRegion::Backed(PhysicalRegion::Shared(region.clone()));
+        if self.address_map.alloc(range.clone(), backing, kind).is_err() {
+            return Err(region);
+        }
 
-        range
+        self.map_pages(at, region.physical_addresses(), flags, region.page_size());
+
+        Ok(range)
+    }
+
+    /// Map each of `physical_addresses` in turn as a `size` page, starting at
+    /// `at`
+    fn map_pages(
+        &mut self,
+        at: VirtualAddress,
+        physical_addresses: impl Iterator<Item = PhysicalAddress>,
+        flags: Flags,
+        size: PageSize,
+    ) {
+        for (i, phys_addr) in physical_addresses.enumerate() {
+            let virt_addr = at.add(i * size.to_byte_size());
+            self.table.map(phys_addr, virt_addr, flags, size);
+            sfence(Some(virt_addr), None);
+        }
     }
 
     /// Place a guard page at the given [`VirtualAddress`]
@@ -195,6 +256,13 @@ impl MemoryManager {
 
         let iter = (0..region.page_count()).map(|i| at.add(i * region.page_size().to_byte_size()));
         for virt_addr in iter {
+            // Lazy regions only have the pages which have been touched mapped
+            if let MemoryRegion::Lazy { .. } = region {
+                if self.table.page_flags(virt_addr).is_none() {
+                    continue;
+                }
+            }
+
             self.table.unmap(virt_addr);
             sfence(Some(virt_addr), None);
         }
@@ -202,6 +270,42 @@ impl MemoryManager {
         region
     }
 
+    /// Shrink the shared region starting at the given [`VirtualAddress`] down
+    /// to its first `n_pages` pages, unmapping and freeing the rest. Returns
+    /// `None` without changing anything if there's no such region, it's
+    /// smaller than `n_pages`, or its backing memory is shared with something
+    /// else and so can't be freed.
+    pub fn shrink_shared_region(&mut self, at: VirtualAddress, n_pages: usize) -> Option<Range<VirtualAddress>> {
+        let (span, kind, page_size, old_n_pages) = match self.address_map.find(at)? {
+            AddressRegion { region: Some(MemoryRegion::Backed(PhysicalRegion::Shared(shared))), span, kind }
+                if span.start == at && n_pages > 0 && n_pages <= shared.n_pages() && shared.is_unique() =>
+            {
+                (span.clone(), *kind, shared.page_size(), shared.n_pages())
+            }
+            _ => return None,
+        };
+
+        let mut region = self.address_map.free(span).unwrap();
+
+        for i in n_pages..old_n_pages {
+            let virt_addr = at.add(i * page_size.to_byte_size());
+            self.table.unmap(virt_addr);
+            sfence(Some(virt_addr), None);
+        }
+
+        match &mut region {
+            MemoryRegion::Backed(PhysicalRegion::Shared(shared)) => {
+                shared.get_mut().expect("backing was checked to be unshared above").truncate(n_pages)
+            }
+            _ => unreachable!(),
+        }
+
+        let range = at..at.add(n_pages * page_size.to_byte_size());
+        self.address_map.alloc(range.clone(), region, kind).unwrap();
+
+        Some(range)
+    }
+
     /// Returns the [`AddressRegion`] that contains the given
     /// [`VirtualAddress`], if it exists
     pub fn region_for(&self, at: VirtualAddress) -> Option<&AddressRegion> {
@@ -271,6 +375,12 @@ impl MemoryManager {
         self.table.resolve(virt)
     }
 
+    /// The size of the page the given [`VirtualAddress`] is mapped with, if
+    /// it's mapped
+    pub fn page_size(&self, virt: VirtualAddress) -> Option<PageSize> {
+        self.table.page_size(virt)
+    }
+
     /// The [`PhysicalAddress`] of the contained [`PageTable`]
     pub fn table_phys_address(&self) -> PhysicalAddress {
         self.table.physical_address()
@@ -290,6 +400,15 @@ impl MemoryManager {
     /// [`PageSize`] and number of pages. The method will pick a random
     /// [`VirtualAddress`] that is suitable.
     pub fn find_free_region(&self, size: PageSize, n_pages: usize) -> VirtualAddress {
+        match self.try_find_free_region(size, n_pages) {
+            Some(at) => at,
+            None => todo!("exhausted address space -- this should be an `Err(...)` in the future"),
+        }
+    }
+
+    /// Same as [`Self::find_free_region`], but returns `None` when there's no
+    /// unoccupied region large enough
+    pub fn try_find_free_region(&self, size: PageSize, n_pages: usize) -> Option<VirtualAddress> {
         let total_bytes = n_pages * size.to_byte_size();
 
         // FIXME: there's probably a better way to do this
@@ -313,7 +432,7 @@ impl MemoryManager {
 
             log::debug!("Found unoccupied region: {:#p}-{:#p}", aligned_start, region.end);
             if region_size >= total_bytes {
-                return aligned_start;
+                return Some(aligned_start);
             }
         }
 
@@ -330,11 +449,11 @@ impl MemoryManager {
 
             log::debug!("Found unoccupied region: {:#p}-{:#p}", aligned_start, end);
             if region_size >= total_bytes {
-                return aligned_start;
+                return Some(aligned_start);
             }
         }
 
-        todo!("exhausted address space -- this should be an `Err(...)` in the future")
+        None
     }
 
     fn find_free_region_with_guards(&self, size: PageSize, n_pages: usize) -> VirtualAddress {
@@ -399,3 +518,173 @@ impl MemoryManager {
         todo!("exhausted address space -- this should be an `Err(...)` in the future")
     }
 }
+
+/// Gigapages are only used when the requested address (if any) is gigapage
+/// aligned, otherwise the same amount of memory is mapped with megapages. The
+/// physical allocator only hands out gigapages as part of a contiguous
+/// allocation, so gigapage regions are always contiguous.
+fn shared_region_layout(
+    at: Option<VirtualAddress>,
+    size: PageSize,
+    len: usize,
+    contiguous: bool,
+) -> (PageSize, usize, bool) {
+    match (size, at) {
+        (PageSize::Gigapage, Some(at)) if at.as_usize() % PageSize::Gigapage.to_byte_size() != 0 => {
+            let megapages_per_gigapage = PageSize::Gigapage.to_byte_size() / PageSize::Megapage.to_byte_size();
+            (PageSize::Megapage, len * megapages_per_gigapage, contiguous)
+        }
+        (PageSize::Gigapage, _) => (PageSize::Gigapage, len, true),
+        _ => (size, len, contiguous),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gigapage_shared_region_layout() {
+        let aligned = VirtualAddress::new(4.gib());
+        let unaligned = VirtualAddress::new(4.gib() + 2.mib());
+
+        assert_eq!(shared_region_layout(None, PageSize::Gigapage, 1, false), (PageSize::Gigapage, 1, true));
+        assert_eq!(shared_region_layout(Some(aligned), PageSize::Gigapage, 2, false), (PageSize::Gigapage, 2, true));
+        assert_eq!(
+            shared_region_layout(Some(unaligned), PageSize::Gigapage, 1, false),
+            (PageSize::Megapage, 512, false)
+        );
+        assert_eq!(shared_region_layout(Some(unaligned), PageSize::Megapage, 3, false), (PageSize::Megapage, 3, false));
+    }
+
+    #[test]
+    fn gigapage_shared_region_mapped_with_gigapages() {
+        let mut manager = MemoryManager::new();
+        let at = VirtualAddress::new(4.gib());
+        let (size, len, _) = shared_region_layout(Some(at), PageSize::Gigapage, 2, false);
+
+        // Nothing is read or written through the mapping, so there doesn't
+        // need to be any memory behind it
+        let physical_addresses = (0..len).map(|i| PhysicalAddress::new(8.gib() + i * size.to_byte_size()));
+        manager.map_pages(at, physical_addresses, flags::READ | flags::WRITE | flags::USER | flags::VALID, size);
+
+        for offset in [0, 1.gib() - 4.kib(), 1.gib(), 2.gib() - 4.kib()] {
+            assert_eq!(manager.page_size(at.add(offset)), Some(PageSize::Gigapage));
+        }
+
+        assert_eq!(manager.resolve(at.add(1.gib())), Some(PhysicalAddress::new(9.gib())));
+        assert_eq!(manager.page_size(at.add(2.gib())), None);
+    }
+
+    #[test]
+    fn shrink_shared_region() {
+        let mut manager = MemoryManager::new();
+        let (range, backing) = manager.alloc_shared_region(
+            None,
+            RegionDescription {
+                size: PageSize::Kilopage,
+                len: 4,
+                contiguous: false,
+                flags: flags::READ | flags::WRITE | flags::USER | flags::VALID,
+                fill: FillOption::Zeroed,
+                kind: AddressRegionKind::Channel,
+            },
+        );
+
+        // Still referenced by `backing`, so the pages can't be freed yet
+        assert_eq!(manager.shrink_shared_region(range.start, 1), None);
+        drop(backing);
+
+        assert_eq!(manager.shrink_shared_region(range.start, 5), None);
+
+        let shrunk = manager.shrink_shared_region(range.start, 1).unwrap();
+        assert_eq!(shrunk, range.start..range.start.add(4.kib()));
+        assert_eq!(manager.region_for(range.start).unwrap().span, shrunk);
+        assert!(manager.page_flags(range.start.add(4.kib())).is_none());
+    }
+
+    #[test]
+    fn shared_region_is_mapped_up_front() {
+        let mut manager = MemoryManager::new();
+        let (range, _) = manager.alloc_shared_region(
+            None,
+            RegionDescription {
+                size: PageSize::Kilopage,
+                len: 8,
+                contiguous: false,
+                flags: flags::READ | flags::WRITE | flags::USER | flags::VALID,
+                fill: FillOption::Zeroed,
+                kind: AddressRegionKind::Channel,
+            },
+        );
+
+        // Every page must already be writable so that channel messages never
+        // take a fault when they're first written to
+        for i in 0..8 {
+            let page_flags = manager.page_flags(range.start.add(i * 4.kib())).unwrap();
+            assert!(page_flags & flags::VALID && page_flags & flags::WRITE);
+        }
+    }
+
+    #[test]
+    fn shared_region_freed_by_last_holder() {
+        let mut sender = MemoryManager::new();
+        let (range, backing) = sender.alloc_shared_region(
+            None,
+            RegionDescription {
+                size: PageSize::Kilopage,
+                len: 1,
+                contiguous: false,
+                flags: flags::READ | flags::WRITE | flags::USER | flags::VALID,
+                fill: FillOption::Zeroed,
+                kind: AddressRegionKind::Channel,
+            },
+        );
+
+        let read_only = flags::READ | flags::USER | flags::VALID;
+        let mut receiver_a = MemoryManager::new();
+        let mut receiver_b = MemoryManager::new();
+        let a_range =
+            receiver_a.apply_shared_region(None, read_only, backing.clone(), AddressRegionKind::Channel).unwrap();
+        let b_range =
+            receiver_b.apply_shared_region(None, read_only, backing.clone(), AddressRegionKind::Channel).unwrap();
+        sender.dealloc_region(range.start);
+
+        assert!(!(receiver_a.page_flags(a_range.start).unwrap() & flags::WRITE));
+        assert!(!(receiver_b.page_flags(b_range.start).unwrap() & flags::WRITE));
+
+        // `backing` is only kept around to watch the count, the receivers are
+        // what keep the memory alive
+        assert_eq!(backing.holders(), 3);
+        receiver_a.dealloc_region(a_range.start);
+        assert_eq!(backing.holders(), 2);
+        receiver_b.dealloc_region(b_range.start);
+        assert!(backing.is_unique());
+    }
+
+    #[test]
+    fn apply_shared_region_hands_back_region_when_full() {
+        let mut sender = MemoryManager::new();
+        let (range, backing) = sender.alloc_shared_region(
+            None,
+            RegionDescription {
+                size: PageSize::Kilopage,
+                len: 1,
+                contiguous: false,
+                flags: flags::READ | flags::WRITE | flags::USER | flags::VALID,
+                fill: FillOption::Zeroed,
+                kind: AddressRegionKind::Channel,
+            },
+        );
+
+        // The sender's own mapping stands in for a receiver with no room left
+        // at the requested address
+        let read_only = flags::READ | flags::USER | flags::VALID;
+        let returned = sender.apply_shared_region(Some(range.start), read_only, backing, AddressRegionKind::Channel);
+        let backing = returned.unwrap_err();
+
+        assert_eq!(backing.holders(), 2);
+        assert_eq!(sender.region_for(range.start).unwrap().span, range);
+        assert!(sender.page_flags(range.start).unwrap() & flags::WRITE);
+    }
+}
@@ -49,6 +49,14 @@ pub fn sfence(vaddr: Option<paging::VirtualAddress>, asid: Option<u16>) {
     }
 }
 
+/// Order all loads and stores made by this hart before the fence, including
+/// ones made by usermode before trapping into the kernel, before any that come
+/// after it as observed by other harts
+#[inline(always)]
+pub fn fence() {
+    unsafe { asm!("fence rw, rw") };
+}
+
 pub fn alloc_kernel_stack(size: usize) -> *mut u8 {
     assert!(size.is_power_of_two());
     assert_eq!(size % 4096, 0);
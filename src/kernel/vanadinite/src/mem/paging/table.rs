@@ -127,6 +127,10 @@ impl PageTable {
         self.with_entry(address, |e, _| e.ppn()).flatten()
     }
 
+    pub fn page_size(&self, address: VirtualAddress) -> Option<PageSize> {
+        self.with_entry(address, |_, size| size)
+    }
+
     pub fn physical_address(&self) -> PhysicalAddress {
         virt2phys(VirtualAddress::from_ptr(&*self.root))
     }
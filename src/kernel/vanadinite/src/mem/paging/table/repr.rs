@@ -7,7 +7,10 @@
 
 use core::ops::Range;
 
-use crate::{mem::paging::table::flags::*, utils::Units};
+use crate::{
+    mem::paging::table::flags::*,
+    utils::{self, Units},
+};
 
 // Default to Sv39
 #[cfg(all(not(feature = "paging.sv48"), not(feature = "paging.sv57")))]
@@ -377,6 +380,23 @@ impl PageSize {
         }
     }
 
+    /// The number of pages of this size needed to hold `size` bytes
+    pub fn pages_for(self, size: usize) -> usize {
+        self.align_up(size) / self.to_byte_size()
+    }
+
+    /// Same as [`Self::pages_for`], but returns `None` when `size` is so close
+    /// to `usize::MAX` that rounding it up to a whole page would overflow
+    pub fn checked_pages_for(self, size: usize) -> Option<usize> {
+        let page_size = self.to_byte_size();
+        Some(size.checked_add(page_size - 1)? / page_size)
+    }
+
+    /// Round `addr` up to the next multiple of the page size
+    pub fn align_up(self, addr: usize) -> usize {
+        utils::round_up_to_next(addr, self.to_byte_size())
+    }
+
     pub fn next(self) -> Option<Self> {
         match self {
             PageSize::Kilopage => None,
@@ -21,3 +21,26 @@ fn virtual_addr_checks() {
     assert!(VirtualAddress::userspace_range().end.checked_add(0xffffff8000000000).is_none());
     assert!(VirtualAddress::kernelspace_range().start.checked_offset(-1).is_none());
 }
+
+#[test]
+fn page_size_rounding() {
+    assert_eq!(PageSize::Kilopage.pages_for(0), 0);
+    assert_eq!(PageSize::Kilopage.pages_for(4096), 1);
+    assert_eq!(PageSize::Kilopage.pages_for(4097), 2);
+    assert_eq!(PageSize::Megapage.pages_for(4096), 1);
+    assert_eq!(PageSize::Megapage.pages_for(2 * 1024 * 1024 + 1), 2);
+    assert_eq!(PageSize::Gigapage.pages_for(1024 * 1024 * 1024), 1);
+
+    assert_eq!(PageSize::Kilopage.align_up(0), 0);
+    assert_eq!(PageSize::Kilopage.align_up(4096), 4096);
+    assert_eq!(PageSize::Kilopage.align_up(4097), 8192);
+    assert_eq!(PageSize::Megapage.align_up(4096), 2 * 1024 * 1024);
+}
+
+#[test]
+fn page_size_rounding_overflow() {
+    assert_eq!(PageSize::Kilopage.checked_pages_for(4097), Some(2));
+    assert_eq!(PageSize::Kilopage.checked_pages_for(usize::MAX - 4095), Some(usize::MAX / 4096));
+    assert_eq!(PageSize::Kilopage.checked_pages_for(usize::MAX), None);
+    assert_eq!(PageSize::Megapage.checked_pages_for(usize::MAX - 4096), None);
+}
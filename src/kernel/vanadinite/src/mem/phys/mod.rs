@@ -9,6 +9,7 @@ pub mod bitmap;
 
 use crate::mem::paging::PhysicalAddress;
 use bitmap::BitmapAllocator;
+use core::ops::Range;
 use sync::SpinMutex;
 
 use super::paging::PageSize;
@@ -78,6 +79,20 @@ impl PhysicalPage {
     }
 }
 
+/// The most `/memory` regions that are checked by
+/// [`find_overlapping_regions`] during boot, since there's no heap to collect
+/// them into yet
+pub const MAX_MEMORY_REGIONS: usize = 16;
+
+/// Sort the physical memory regions by their starting address, returning the
+/// first pair that overlap each other, if any. Overlapping regions mean the
+/// device tree is malformed, and believing it could hand out the same memory
+/// twice.
+pub fn find_overlapping_regions(regions: &mut [Range<usize>]) -> Option<(Range<usize>, Range<usize>)> {
+    regions.sort_unstable_by_key(|region| region.start);
+    regions.windows(2).find(|pair| pair[0].end > pair[1].start).map(|pair| (pair[0].clone(), pair[1].clone()))
+}
+
 pub fn alloc_page() -> PhysicalPage {
     unsafe { PHYSICAL_MEMORY_ALLOCATOR.lock().alloc(PageSize::Kilopage).expect("out of memory") }
 }
@@ -94,3 +109,21 @@ pub fn zalloc_page() -> PhysicalPage {
 
     page
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overlapping_memory_regions() {
+        let mut disjoint = [0x9000_0000..0xA000_0000, 0x8000_0000..0x9000_0000];
+        assert_eq!(find_overlapping_regions(&mut disjoint), None);
+        assert_eq!(disjoint, [0x8000_0000..0x9000_0000, 0x9000_0000..0xA000_0000]);
+
+        let mut overlapping = [0x8800_0000..0x9800_0000, 0xC000_0000..0xC100_0000, 0x8000_0000..0x9000_0000];
+        assert_eq!(
+            find_overlapping_regions(&mut overlapping),
+            Some((0x8000_0000..0x9000_0000, 0x8800_0000..0x9800_0000))
+        );
+    }
+}
@@ -5,8 +5,12 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
-use super::{paging::PageSize, PhysicalAddress};
+use super::{
+    paging::{flags::Flags, PageSize},
+    PhysicalAddress,
+};
 use crate::mem::{
+    manager::FillOption,
     phys::{PhysicalMemoryAllocator, PhysicalPage, PHYSICAL_MEMORY_ALLOCATOR},
     phys2virt,
 };
@@ -15,7 +19,15 @@ use alloc::{sync::Arc, vec::Vec};
 #[derive(Debug, PartialEq)]
 pub enum MemoryRegion {
     Backed(PhysicalRegion),
-    Lazy { page_size: PageSize, n_pages: usize },
+    /// Memory which is only allocated, zeroed, and mapped with `flags` a page
+    /// at a time as each page is first touched, with `backing` holding the
+    /// pages allocated so far
+    Lazy {
+        page_size: PageSize,
+        n_pages: usize,
+        flags: Flags,
+        backing: Vec<UniquePhysicalRegion>,
+    },
     GuardPage,
 }
 
@@ -141,15 +153,56 @@ impl UniquePhysicalRegion {
     }
 
     pub fn zero(&mut self) {
+        self.fill_bytes(0);
+    }
+
+    /// Fill the region as described by `fill`
+    pub fn fill(&mut self, fill: FillOption<'_>) {
+        match fill {
+            FillOption::Data(data) => self.copy_data_into(data),
+            FillOption::Zeroed => self.zero(),
+            FillOption::Pattern(byte) => self.fill_bytes(byte),
+            FillOption::Unitialized => {}
+        }
+    }
+
+    fn fill_bytes(&mut self, byte: u8) {
         for phys_addr in self.physical_addresses() {
             let copy_to = unsafe {
                 core::slice::from_raw_parts_mut(phys2virt(phys_addr).as_mut_ptr(), self.page_size.to_byte_size())
             };
 
-            copy_to.fill(0);
+            copy_to.fill(byte);
         }
     }
 
+    /// Free every page of the region after the first `n_pages`
+    pub fn truncate(&mut self, n_pages: usize) {
+        assert!(n_pages <= self.n_pages, "can't truncate a region to a larger size");
+
+        let n_freed = self.n_pages - n_pages;
+        match &mut self.kind {
+            PhysicalRegionKind::Contiguous(_) if n_freed == 0 => {}
+            PhysicalRegionKind::Contiguous(start) => unsafe {
+                let first_freed = start.as_phys_address().offset(n_pages * self.page_size.to_byte_size());
+                PHYSICAL_MEMORY_ALLOCATOR.lock().dealloc_contiguous(
+                    PhysicalPage::from_ptr(first_freed.as_mut_ptr()),
+                    self.page_size,
+                    n_freed,
+                )
+            },
+            PhysicalRegionKind::Sparse(pages) => {
+                let mut allocator = PHYSICAL_MEMORY_ALLOCATOR.lock();
+
+                for page in pages.drain(n_pages..) {
+                    unsafe { allocator.dealloc(page, self.page_size) };
+                }
+            }
+        }
+
+        self.n_pages = n_pages;
+    }
+
     pub fn into_shared_region(self) -> SharedPhysicalRegion {
         SharedPhysicalRegion { region: Arc::new(self) }
     }
@@ -185,6 +238,31 @@ pub struct SharedPhysicalRegion {
     region: Arc<UniquePhysicalRegion>,
 }
 
+impl SharedPhysicalRegion {
+    /// Whether both regions refer to the same allocation of physical memory,
+    /// as opposed to merely having the same contents
+    pub fn is_same_backing(&self, other: &SharedPhysicalRegion) -> bool {
+        Arc::ptr_eq(&self.region, &other.region)
+    }
+
+    /// Whether nothing else holds a reference to the underlying region
+    pub fn is_unique(&self) -> bool {
+        self.holders() == 1
+    }
+
+    /// The number of references to the underlying region, which is freed once
+    /// the last of them is dropped
+    pub fn holders(&self) -> usize {
+        Arc::strong_count(&self.region)
+    }
+
+    /// Mutable access to the underlying region, only possible if it isn't
+    /// shared with anything else
+    pub fn get_mut(&mut self) -> Option<&mut UniquePhysicalRegion> {
+        Arc::get_mut(&mut self.region)
+    }
+}
+
 impl core::ops::Deref for SharedPhysicalRegion {
     type Target = UniquePhysicalRegion;
 
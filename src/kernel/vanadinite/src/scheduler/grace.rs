@@ -0,0 +1,105 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2021 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use alloc::{collections::VecDeque, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use sync::SpinMutex;
+
+/// Holds on to things other harts may still be using until every hart has
+/// passed through a quiescent point since they were retired, somewhere it
+/// can't be in the middle of using them. For tasks that's the scheduler: a hart
+/// can be partway through a syscall with a task that's since died, but it's
+/// done with it by the time it picks the next task to run.
+pub struct GracePeriod<T> {
+    /// Bumped each time something is retired, so that it waits for every hart
+    /// to have seen at least that epoch
+    epoch: AtomicUsize,
+    /// The epoch each hart had reached when it was last quiescent, or
+    /// `usize::MAX` if it's never run anything and so can't be using anything
+    seen: Vec<AtomicUsize>,
+    retired: SpinMutex<VecDeque<(usize, T)>>,
+}
+
+impl<T: Send> GracePeriod<T> {
+    /// Track harts with IDs below `n_harts`. Hart IDs can be sparse, in which
+    /// case the harts that don't exist simply never hold anything up.
+    pub fn new(n_harts: usize) -> Self {
+        Self {
+            epoch: AtomicUsize::new(0),
+            seen: (0..n_harts).map(|_| AtomicUsize::new(usize::MAX)).collect(),
+            retired: SpinMutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Hold on to `item` until every hart has been quiescent after this point
+    pub fn retire(&self, item: T) {
+        // Bumping the epoch under the lock keeps the queue in epoch order
+        let mut retired = self.retired.lock();
+        let epoch = self.epoch.fetch_add(1, Ordering::AcqRel) + 1;
+        retired.push_back((epoch, item));
+    }
+
+    /// Record that `hart` isn't using anything it got hold of before now
+    pub fn quiescent(&self, hart: usize) {
+        self.seen[hart].store(self.epoch.load(Ordering::Acquire), Ordering::Release);
+    }
+
+    /// Take back everything which every hart has been quiescent since retiring
+    pub fn reclaim(&self) -> Vec<T> {
+        let reached = self.seen.iter().map(|seen| seen.load(Ordering::Acquire)).min().unwrap_or(usize::MAX);
+
+        let mut retired = self.retired.lock();
+        let n_ready = retired.iter().take_while(|(epoch, _)| *epoch <= reached).count();
+
+        retired.drain(..n_ready).map(|(_, item)| item).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reclaim_waits_for_every_hart() {
+        let dead_tasks = GracePeriod::new(3);
+
+        // Hart 2 never started, so it can't be holding on to anything
+        dead_tasks.quiescent(0);
+        dead_tasks.quiescent(1);
+        dead_tasks.retire(1);
+        assert!(dead_tasks.reclaim().is_empty());
+
+        // Hart 1 is still in the middle of a syscall which looked the task up
+        // before it died
+        dead_tasks.quiescent(0);
+        assert!(dead_tasks.reclaim().is_empty());
+
+        // Something retired later waits for both harts to see it too
+        dead_tasks.retire(2);
+        dead_tasks.quiescent(1);
+        assert_eq!(dead_tasks.reclaim(), [1]);
+
+        dead_tasks.quiescent(0);
+        assert_eq!(dead_tasks.reclaim(), [2]);
+        assert!(dead_tasks.reclaim().is_empty());
+    }
+
+    #[test]
+    fn missing_hart_ids_never_hold_up_reclaim() {
+        // Only harts 1 and 3 exist, with nothing ever running on 0 or 2
+        let dead_tasks = GracePeriod::new(4);
+
+        dead_tasks.quiescent(1);
+        dead_tasks.quiescent(3);
+        dead_tasks.retire(1);
+        dead_tasks.quiescent(3);
+        assert!(dead_tasks.reclaim().is_empty());
+
+        dead_tasks.quiescent(1);
+        assert_eq!(dead_tasks.reclaim(), [1]);
+    }
+}
@@ -5,6 +5,7 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
+mod grace;
 pub mod round_robin;
 
 use crate::{
@@ -16,10 +17,11 @@ use alloc::{collections::BTreeMap, sync::Arc};
 use core::{
     cell::Cell,
     num::NonZeroUsize,
-    sync::atomic::{AtomicUsize, Ordering},
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
 };
+use grace::GracePeriod;
 use librust::task::Tid;
-use sync::{SpinMutex, SpinRwLock};
+use sync::{Lazy, SpinMutex, SpinRwLock};
 
 pub static SCHEDULER: round_robin::RoundRobinScheduler = round_robin::RoundRobinScheduler::new();
 pub static TASKS: TaskList = TaskList::new();
@@ -27,6 +29,18 @@ pub static TASKS: TaskList = TaskList::new();
 // Used for heuristics in schedulers if they so choose
 static N_TASKS: AtomicUsize = AtomicUsize::new(0);
 
+/// How long a task may run before it's preempted in favor of the next runnable
+/// task, in microseconds. Can be set with the `time-slice` kernel argument.
+pub static TIME_SLICE_US: AtomicU64 = AtomicU64::new(10_000);
+
+// Bitmask of the harts which are currently sitting in `wfi` with nothing to
+// run, so they can be woken by an IPI when a task becomes runnable
+static IDLE_HARTS: AtomicUsize = AtomicUsize::new(0);
+
+// Tasks which have died, waiting for every hart to stop using them before
+// they're removed from `TASKS` and torn down
+static DEAD_TASKS: Lazy<GracePeriod<Tid>> = Lazy::new(|| GracePeriod::new(crate::N_CPUS.load(Ordering::Acquire)));
+
 //pub fn init_scheduler(scheduler: Box<dyn Scheduler>) {
 //    SCHEDULER.0.write().replace(scheduler).expect("reinitialized scheduler!");
 //}
@@ -87,7 +101,81 @@ pub trait Scheduler: Send {
     fn dequeue(&self, tid: Tid);
 }
 
+/// Remove `tid`, which has died and been taken off of its run queue, from
+/// [`TASKS`] once no hart can still be using it, at which point whatever it
+/// still holds is freed. Until then it can still be found in [`TASKS`], with
+/// its state showing that it's dead.
+pub fn retire_task(tid: Tid) {
+    DEAD_TASKS.retire(tid);
+}
+
+/// Called by the scheduler on each hart before it picks the next task to run,
+/// once the hart is done with whatever tasks it was using before
+fn pass_quiescent_point() {
+    DEAD_TASKS.quiescent(crate::HART_ID.get());
+
+    for tid in DEAD_TASKS.reclaim() {
+        drop(TASKS.remove(tid));
+    }
+}
+
+/// Send an IPI to any idle harts (other than the current one) so that they
+/// re-check their run queues, used when a blocked task becomes runnable
+pub fn wake_idle_harts() {
+    let idle = idle_harts_other_than(crate::HART_ID.get());
+
+    if idle != 0 {
+        log::debug!("Waking idle harts: {:#b}", idle);
+        if let Err(e) = sbi::ipi::send_ipi(idle, 0) {
+            log::error!("Failed to wake idle harts: {:?}", e);
+        }
+    }
+}
+
+fn mark_idle(idle: bool) {
+    set_hart_idle(crate::HART_ID.get(), idle);
+}
+
+fn set_hart_idle(hart: usize, idle: bool) {
+    let mask = 1 << hart;
+
+    match idle {
+        true => IDLE_HARTS.fetch_or(mask, Ordering::AcqRel),
+        false => IDLE_HARTS.fetch_and(!mask, Ordering::AcqRel),
+    };
+}
+
+/// The mask of idle harts to send an IPI to, which never includes `hart`
+/// since it's clearly awake if it's asking
+fn idle_harts_other_than(hart: usize) -> usize {
+    IDLE_HARTS.load(Ordering::Acquire) & !(1 << hart)
+}
+
+/// Program the timer interrupt for the end of a fresh time slice. Every switch
+/// into a task starts a new one, so a task which blocks or yields early doesn't
+/// eat into the time slice of the next task to run.
+fn start_time_slice() {
+    sbi::timer::set_timer(time_slice_end(csr::time::read())).unwrap();
+}
+
+/// The timer value at which a time slice starting at `now` runs out
+fn time_slice_end(now: u64) -> u64 {
+    let time_slice = TIME_SLICE_US.load(Ordering::Relaxed);
+    now + ticks_per_us(time_slice, crate::TIMER_FREQ.load(Ordering::Relaxed))
+}
+
+/// Parse the value given to the `time-slice` kernel argument, rejecting a time
+/// slice of zero since every task would be preempted before it could run
+pub fn parse_time_slice(value: Option<&str>) -> Option<u64> {
+    match value?.parse() {
+        Ok(us) if us > 0 => Some(us),
+        _ => None,
+    }
+}
+
 fn sleep() -> ! {
+    mark_idle(true);
+
     sbi::timer::set_timer(csr::time::read() + ticks_per_us(10_000, crate::TIMER_FREQ.load(Ordering::Relaxed))).unwrap();
     csr::sie::enable();
     csr::sstatus::enable_interrupts();
@@ -191,3 +279,55 @@ unsafe extern "C" fn return_to_usermode(_registers: &Context) -> ! {
         sret
     ", options(noreturn));
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn idle_harts_tracked_per_hart() {
+        // Tests only run on one hart, which is busy running them
+        assert_eq!(IDLE_HARTS.load(Ordering::Acquire), 0);
+
+        set_hart_idle(1, true);
+        set_hart_idle(3, true);
+        assert_eq!(idle_harts_other_than(0), 0b1010);
+        assert_eq!(idle_harts_other_than(3), 0b0010);
+
+        // Going idle twice doesn't need to be undone twice
+        set_hart_idle(3, true);
+        set_hart_idle(3, false);
+        assert_eq!(idle_harts_other_than(0), 0b0010);
+
+        // A hart waking up doesn't touch any of the others
+        set_hart_idle(2, false);
+        set_hart_idle(1, false);
+        assert_eq!(idle_harts_other_than(0), 0);
+    }
+
+    #[test]
+    fn time_slice_argument_must_be_nonzero() {
+        assert_eq!(parse_time_slice(Some("2500")), Some(2500));
+        assert_eq!(parse_time_slice(Some("0")), None);
+        assert_eq!(parse_time_slice(Some("-5")), None);
+        assert_eq!(parse_time_slice(Some("soon")), None);
+        assert_eq!(parse_time_slice(None), None);
+    }
+
+    #[test]
+    fn time_slice_ends_after_configured_length() {
+        let freq = crate::TIMER_FREQ.load(Ordering::Relaxed);
+        let default = TIME_SLICE_US.load(Ordering::Relaxed);
+
+        // Each slice is measured from when it starts, not from the last one
+        assert_eq!(time_slice_end(1000), 1000 + ticks_per_us(default, freq));
+        assert_eq!(time_slice_end(5000) - 5000, time_slice_end(1000) - 1000);
+
+        TIME_SLICE_US.store(250, Ordering::Relaxed);
+        let short = time_slice_end(1000);
+        TIME_SLICE_US.store(default, Ordering::Relaxed);
+
+        assert_eq!(short, 1000 + ticks_per_us(250, freq));
+        assert!(1000 < short && short < time_slice_end(1000));
+    }
+}
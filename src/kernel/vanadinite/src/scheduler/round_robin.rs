@@ -5,14 +5,11 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
-use core::sync::atomic::Ordering;
-
 use super::{Scheduler, Task, Tid, CURRENT_TASK, TASKS};
 use crate::{
     csr::{self, satp::Satp},
     mem::{self, paging::SATP_MODE},
     task::TaskState,
-    utils::ticks_per_us,
 };
 use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
 use sync::{Lazy, SpinMutex};
@@ -51,6 +48,9 @@ impl RoundRobinScheduler {
 impl Scheduler for RoundRobinScheduler {
     fn schedule(&self) -> ! {
         log::debug!("Starting scheduling");
+        super::mark_idle(false);
+        super::pass_quiescent_point();
+
         let mut queue = self.current_queue().lock();
         let queue_len = queue.len();
 
@@ -66,7 +66,10 @@ impl Scheduler for RoundRobinScheduler {
             match state {
                 TaskState::Blocked if queue_len > 1 => queue.rotate_left(1),
                 TaskState::Blocked => break,
-                TaskState::Dead => drop(queue.pop_front()),
+                TaskState::Dead(_) => {
+                    super::retire_task(queued_task.tid);
+                    drop(queue.pop_front());
+                }
                 TaskState::Running => {
                     to_run = queue.front();
                     break;
@@ -89,10 +92,7 @@ impl Scheduler for RoundRobinScheduler {
                 drop(task);
                 drop(queue);
 
-                sbi::timer::set_timer(
-                    csr::time::read() + ticks_per_us(10_000, crate::TIMER_FREQ.load(Ordering::Relaxed)),
-                )
-                .unwrap();
+                super::start_time_slice();
 
                 csr::satp::write(Satp { mode: SATP_MODE, asid: tid.value() as u16, root_page_table });
                 mem::sfence(None, None);
@@ -6,50 +6,519 @@
 // obtain one at https://mozilla.org/MPL/2.0/.
 
 use crate::{
+    capabilities::{Capability, CapabilityResource, CapabilityRights},
     mem::{
-        manager::{AddressRegionKind, FillOption, RegionDescription},
-        paging::{flags, PageSize, VirtualAddress},
-        region::{MemoryRegion, PhysicalRegion},
+        self,
+        manager::{AddressRegion, AddressRegionKind, FillOption, RegionDescription},
+        paging::{
+            flags::{self, Flags},
+            PageSize, VirtualAddress,
+        },
+        region::{MemoryRegion, PhysicalRegion, SharedPhysicalRegion},
     },
-    scheduler::{CURRENT_TASK, TASKS},
+    scheduler::{self, CURRENT_TASK, TASKS},
     task::{Task, TaskState},
-    utils::{self, Units},
 };
-use alloc::{collections::BTreeMap, sync::Arc};
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    vec::Vec,
+};
 use core::{
     ops::Range,
     sync::atomic::{AtomicUsize, Ordering},
 };
 use librust::{
-    error::KError,
-    message::{KernelNotification, Message, Sender, SyscallResult},
-    syscalls::channel::{ChannelId, MessageId},
-    task::Tid,
+    capabilities::CapabilityPtr,
+    error::{ChannelArgumentError, KError},
+    message::{KernelNotification, Sender, SyscallResult},
+    syscalls::{
+        channel::{
+            ChannelId, ChannelStats, MessageAccess, MessageId, MAX_CHANNEL_LABEL_LEN, MAX_INITIAL_MESSAGE_BYTES,
+            MAX_READY_CHANNELS,
+        },
+        Syscall,
+    },
+    task::{ExitReason, Tid},
 };
 
+/// The default maximum size of a single message, used when a channel is
+/// created without specifying one
 pub const MAX_CHANNEL_BYTES: usize = 4096;
 
 pub struct UserspaceChannel {
     other_task: Tid,
     other_channel_id: ChannelId,
+    capability: CapabilityPtr,
+    max_message_size: usize,
+    /// The number of messages this end can send before the other end retires
+    /// some, if the channel is flow controlled
+    credits: Option<usize>,
     message_id_counter: Arc<AtomicUsize>,
-    write_regions: BTreeMap<MessageId, Range<VirtualAddress>>,
-    read_regions: BTreeMap<MessageId, (Range<VirtualAddress>, usize)>,
+    write_regions: BTreeMap<MessageId, OutgoingMessage>,
+    read_regions: BTreeMap<MessageId, ReceivedMessage>,
+    messages_created: usize,
+    messages_sent: usize,
+    messages_read: usize,
+    messages_retired: usize,
+    bytes_sent: usize,
+    /// The most messages there have been received and not yet retired at once
+    peak_pending_reads: usize,
+    /// A human-readable name for the channel set by the task for debugging
+    label: Option<Box<str>>,
+    /// Whether the task is blocked in `send_message_blocking` until this end
+    /// of the channel gets a credit back
+    sender_blocked: bool,
+    /// The number of handles to this end of the channel the task has open,
+    /// counting the channel's own ID and any made with [`dup_channel`]
+    handles: usize,
+    /// Whether the channel's own ID has been closed while duplicates of it are
+    /// still open, so that only the duplicates can be used
+    original_closed: bool,
+    /// Whether the other end of the channel has been closed, either by its task
+    /// or by its task dying. Messages it sent before then can still be read.
+    peer_closed: bool,
+    /// Why the other end's task died, if that's what closed it. The task may
+    /// be long gone by the time the channel is reclaimed, so this is recorded
+    /// as it dies.
+    peer_exit: Option<ExitReason>,
+}
+
+/// A message created with [`create_message`] which hasn't been sent yet
+struct OutgoingMessage {
+    region: Range<VirtualAddress>,
+    /// The physical memory minted for the message, which must still be what's
+    /// mapped at `region` for it to be sent
+    backing: SharedPhysicalRegion,
+}
+
+/// A message which has been sent from the other end of the channel and not
+/// yet retired
+struct ReceivedMessage {
+    region: Range<VirtualAddress>,
+    len: usize,
+    /// The ID of the message this is in reply to, if any
+    reply_to: Option<MessageId>,
+    /// The position of the message in the sequence of messages sent by the
+    /// other end of the channel, starting at 1
+    sequence: usize,
+    /// An opaque value the sender attached to the message, zero if none
+    tag: u64,
+    /// The task which sent the message
+    sender: Tid,
+}
+
+impl ReceivedMessage {
+    /// The message as it's returned to the task by [`read_message`]
+    fn to_parts(
+        &self,
+        id: MessageId,
+        access: MessageAccess,
+    ) -> (usize, usize, usize, usize, usize, usize, usize, usize, usize) {
+        (
+            id.value(),
+            self.region.start.as_usize(),
+            self.len,
+            self.region.end.as_usize() - self.region.start.as_usize(),
+            access as usize,
+            self.reply_to.map_or(0, MessageId::value),
+            self.sequence,
+            self.tag as usize,
+            self.sender.value(),
+        )
+    }
 }
 
 impl UserspaceChannel {
+    fn new(
+        other_task: Tid,
+        other_channel_id: ChannelId,
+        capability: CapabilityPtr,
+        max_message_size: usize,
+        credits: Option<usize>,
+        message_id_counter: Arc<AtomicUsize>,
+    ) -> Self {
+        Self {
+            other_task,
+            other_channel_id,
+            capability,
+            max_message_size,
+            credits,
+            message_id_counter,
+            write_regions: BTreeMap::new(),
+            read_regions: BTreeMap::new(),
+            messages_created: 0,
+            messages_sent: 0,
+            messages_read: 0,
+            messages_retired: 0,
+            bytes_sent: 0,
+            peak_pending_reads: 0,
+            label: None,
+            sender_blocked: false,
+            handles: 1,
+            original_closed: false,
+            peer_closed: false,
+            peer_exit: None,
+        }
+    }
+
     fn next_message_id(&self) -> usize {
         self.message_id_counter.fetch_add(1, Ordering::AcqRel)
     }
+
+    fn consume_credit(&mut self) {
+        if let Some(credits) = &mut self.credits {
+            *credits -= 1;
+        }
+    }
+
+    /// Give back `n` credits, returning whether the task was blocked waiting
+    /// for one so that it can be woken
+    fn return_credits(&mut self, n: usize) -> bool {
+        if let Some(credits) = &mut self.credits {
+            *credits += n;
+        }
+
+        core::mem::take(&mut self.sender_blocked)
+    }
+
+    /// Account for a message of `len` bytes being sent from this end, returning
+    /// its position in the sequence of messages sent
+    fn record_send(&mut self, len: usize) -> usize {
+        self.consume_credit();
+        self.messages_sent += 1;
+        self.bytes_sent += len;
+
+        self.messages_sent
+    }
+
+    /// Queue a message sent from the other end of the channel to be read
+    fn receive(&mut self, message_id: MessageId, message: ReceivedMessage) {
+        self.read_regions.insert(message_id, message);
+        self.peak_pending_reads = self.peak_pending_reads.max(self.read_regions.len());
+    }
+
+    fn stats(&self) -> ChannelStats {
+        ChannelStats {
+            created: self.messages_created,
+            sent: self.messages_sent,
+            read: self.messages_read,
+            retired: self.messages_retired,
+            bytes_sent: self.bytes_sent,
+            pending_writes: self.write_regions.len(),
+            pending_reads: self.read_regions.len(),
+            peak_pending_reads: self.peak_pending_reads,
+        }
+    }
+
+    /// Drop one of the handles to the channel, returning whether it was the
+    /// last one and so the channel should be closed
+    fn drop_handle(&mut self) -> bool {
+        self.handles -= 1;
+        self.handles == 0
+    }
+
+    /// Connect this end of the channel to `peer`'s end `peer_channel_id` in
+    /// place of the proxy it was connected to, taking over `proxy_credits`, the
+    /// proxy's remaining budget for sending to the peer. `moved` messages from
+    /// this end were still pending at the proxy and are now pending at the
+    /// peer, so they come out of that budget. Returns whether the task was blocked on
+    /// the proxy running out of credits so that it can be woken to try again.
+    fn rebind(
+        &mut self,
+        peer: Tid,
+        peer_channel_id: ChannelId,
+        proxy_credits: Option<usize>,
+        moved: usize,
+        message_id_counter: Arc<AtomicUsize>,
+    ) -> bool {
+        self.other_task = peer;
+        self.other_channel_id = peer_channel_id;
+        self.credits = proxy_credits.map(|credits| credits.saturating_sub(moved));
+        self.message_id_counter = message_id_counter;
+
+        core::mem::take(&mut self.sender_blocked)
+    }
+
+    fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    /// Replace the channel's label, or remove it if `label` is empty
+    fn set_label(&mut self, label: &str) -> Result<(), KError> {
+        if label.len() > MAX_CHANNEL_LABEL_LEN {
+            return Err(KError::InvalidArgument(1));
+        }
+
+        self.label = match label {
+            "" => None,
+            label => Some(label.into()),
+        };
+
+        Ok(())
+    }
+}
+
+/// Whether the task can have another channel opened without going over its
+/// limit
+fn has_channel_room(task: &Task) -> bool {
+    task.channels.len() + task.channel_dups.len() < task.channel_limit
+}
+
+/// Find the channel that the handle `channel_id` refers to, which is either
+/// the channel's own ID or one made with [`dup_channel`]. Handles that aren't
+/// open are passed through as-is for the syscall to reject.
+pub fn resolve_handle(task: &Task, channel_id: usize) -> SyscallResult<usize, KError> {
+    let id = ChannelId::new(channel_id);
+    match task.channel_dups.get(&id) {
+        Some(original) => SyscallResult::Ok(original.value()),
+        None => match task.channels.get(&id) {
+            Some(channel) if channel.original_closed => {
+                SyscallResult::Err(KError::InvalidChannelArgument(0, ChannelArgumentError::UnknownChannel))
+            }
+            _ => SyscallResult::Ok(channel_id),
+        },
+    }
+}
+
+/// A handle to the channel `channel_id` which is still open, for telling the
+/// task about the channel once its own ID may have been closed
+fn open_handle(task: &Task, channel_id: ChannelId) -> ChannelId {
+    match task.channels[&channel_id].original_closed {
+        false => channel_id,
+        true => task.channel_dups.iter().find(|(_, original)| **original == channel_id).map(|(dup, _)| *dup).unwrap(),
+    }
+}
+
+/// Create another handle to the channel `channel_id` in the same task, which
+/// can be used exactly like the original, e.g. to hand to a different part of
+/// the task. Messages belong to the channel rather than to any one handle, so
+/// they can be read, retired, or sent through any of them. The channel stays
+/// open until every handle to it has been closed with [`close_channel`].
+pub fn dup_channel(task: &mut Task, channel_id: usize) -> SyscallResult<usize, KError> {
+    let original = ChannelId::new(resolve_handle(task, channel_id)?);
+    if !task.channels.contains_key(&original) {
+        return SyscallResult::Err(KError::InvalidChannelArgument(0, ChannelArgumentError::UnknownChannel));
+    }
+
+    if !has_channel_room(task) {
+        return SyscallResult::Err(KError::ChannelLimitReached);
+    }
+
+    let dup = ChannelId::new(task.channel_next_id);
+    task.channel_next_id += 1;
+    task.channel_dups.insert(dup, original);
+    task.channels.get_mut(&original).unwrap().handles += 1;
+
+    SyscallResult::Ok(dup.value())
+}
+
+/// Close a handle to a channel, and once it's the last open handle to the
+/// channel, close the task's end of the channel too, freeing any messages
+/// still on it. The other end of the channel is reclaimed by its task with
+/// [`gc_channels`].
+pub fn close_channel(task: &mut Task, channel_id: usize) -> SyscallResult<(), KError> {
+    let id = ChannelId::new(channel_id);
+    let original = match task.channel_dups.remove(&id) {
+        Some(original) => original,
+        None => match task.channels.get_mut(&id) {
+            Some(channel) if !channel.original_closed => {
+                channel.original_closed = true;
+                id
+            }
+            _ => return SyscallResult::Err(KError::InvalidChannelArgument(0, ChannelArgumentError::UnknownChannel)),
+        },
+    };
+
+    if !task.channels.get_mut(&original).unwrap().drop_handle() {
+        return SyscallResult::Ok(());
+    }
+
+    let channel = task.channels.remove(&original).unwrap();
+    task.cspace.revoke(channel.capability);
+    free_channel_messages(task, channel.write_regions, channel.read_regions);
+
+    if let Some(other) = TASKS.get(channel.other_task) {
+        close_peer_end(&mut other.lock(), channel.other_channel_id, None);
+    }
+
+    SyscallResult::Ok(())
+}
+
+/// Mark `other`'s end of a channel, `other_channel_id`, as closed now that
+/// the end it was connected to has gone away, because its task died for
+/// `reason` if there is one. If `other` is blocked sending or waiting for a
+/// message, it's woken to find out.
+fn close_peer_end(other: &mut Task, other_channel_id: ChannelId, reason: Option<ExitReason>) {
+    let other_channel = match other.channels.get_mut(&other_channel_id) {
+        Some(other_channel) => other_channel,
+        None => return,
+    };
+
+    other_channel.peer_closed = true;
+    other_channel.peer_exit = reason;
+    if core::mem::take(&mut other_channel.sender_blocked) {
+        unblock(other);
+    }
+
+    wake_receiver(other);
+}
+
+/// Unmap the messages left on a channel which is being closed
+fn free_channel_messages(
+    task: &mut Task,
+    write_regions: BTreeMap<MessageId, OutgoingMessage>,
+    read_regions: BTreeMap<MessageId, ReceivedMessage>,
+) {
+    let regions = write_regions.into_values().map(|m| m.region).chain(read_regions.into_values().map(|m| m.region));
+
+    for region in regions {
+        dealloc_message_region(task, region);
+    }
+}
+
+/// What the frames of a message are filled with once nothing has them mapped
+/// anymore in debug builds, so that reading a message after it's been freed
+/// shows up as obvious garbage rather than plausible looking zeroes
+#[cfg(debug_assertions)]
+const FREED_MESSAGE_FILL: FillOption<'static> = FillOption::Pattern(0xAA);
+
+/// Unmap a message region which the task is done with, e.g. once the message
+/// has been retired
+fn dealloc_message_region(task: &mut Task, region: Range<VirtualAddress>) {
+    let freed = task.memory_manager.dealloc_region(region.start);
+    task.shared_memory_bytes -= region.end.as_usize() - region.start.as_usize();
+    poison_freed_message(freed);
+}
+
+/// Fill the frames of a message with [`FREED_MESSAGE_FILL`] before they're
+/// freed, if this was the last mapping of them. Does nothing in release builds.
+fn poison_freed_message(region: MemoryRegion) {
+    #[cfg(debug_assertions)]
+    if let MemoryRegion::Backed(PhysicalRegion::Shared(mut backing)) = region {
+        if let Some(backing) = backing.get_mut() {
+            backing.fill(FREED_MESSAGE_FILL);
+        }
+    }
+
+    #[cfg(not(debug_assertions))]
+    let _ = region;
+}
+
+/// Whether `other` still has its end of `channel` open. This is checked
+/// before anything is removed from the sender so that sending to a closed
+/// channel doesn't lose the message.
+fn peer_channel_open(other: &Task, channel: &UserspaceChannel) -> bool {
+    !channel.peer_closed && !other.state.is_dead() && other.channels.contains_key(&channel.other_channel_id)
+}
+
+/// Wake `task` if it's blocked in [`receive_any`], now that a message has
+/// arrived on one of its channels or one of them has closed
+fn wake_receiver(task: &mut Task) {
+    if task.waiting_for_message {
+        task.waiting_for_message = false;
+        unblock(task);
+    }
+}
+
+/// Let a task which blocked on one of its channels be scheduled again
+fn unblock(task: &mut Task) {
+    if matches!(task.state, TaskState::Blocked) {
+        task.state = TaskState::Running;
+        scheduler::wake_idle_harts();
+    }
 }
 
-pub fn request_channel(from: &mut Task, to: Tid) -> SyscallResult<Message, KError> {
+/// Which end of a channel a message region is expected to be on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MessageDirection {
+    /// Created by this task and not yet sent
+    Outgoing,
+    /// Received from the other end of the channel and not yet retired
+    Incoming,
+}
+
+/// Look up the region backing `message_id` on the task's end of `channel_id`,
+/// making sure that it's still mapped into the task before it's used
+fn validate_message_region(
+    task: &Task,
+    channel_id: ChannelId,
+    message_id: MessageId,
+    direction: MessageDirection,
+) -> SyscallResult<Range<VirtualAddress>, KError> {
+    let channel = match task.channels.get(&channel_id) {
+        Some(channel) => channel,
+        None => return SyscallResult::Err(KError::InvalidChannelArgument(0, ChannelArgumentError::UnknownChannel)),
+    };
+
+    let region = match direction {
+        MessageDirection::Outgoing => channel.write_regions.get(&message_id).map(|message| &message.region),
+        MessageDirection::Incoming => channel.read_regions.get(&message_id).map(|message| &message.region),
+    };
+
+    match region {
+        Some(region) if task.memory_manager.region_for(region.start).map(|r| &r.span) == Some(region) => {
+            SyscallResult::Ok(region.clone())
+        }
+        _ => SyscallResult::Err(KError::InvalidChannelArgument(1, ChannelArgumentError::UnknownMessage)),
+    }
+}
+
+/// Tasks waiting for a channel to be opened with them, in the order they
+/// requested one. Servers can accept the oldest request first so that no
+/// requester is starved just because of its [`Tid`].
+#[derive(Debug, Default)]
+pub struct ChannelRequests {
+    pending: VecDeque<Tid>,
+}
+
+impl ChannelRequests {
+    /// Queue a request from `tid`, keeping its place in line if it already
+    /// has one pending
+    pub fn insert(&mut self, tid: Tid) {
+        if !self.contains(&tid) {
+            self.pending.push_back(tid);
+        }
+    }
+
+    pub fn contains(&self, tid: &Tid) -> bool {
+        self.pending.contains(tid)
+    }
+
+    /// Remove the request from `tid`, returning whether there was one
+    pub fn remove(&mut self, tid: &Tid) -> bool {
+        match self.pending.iter().position(|pending| pending == tid) {
+            Some(index) => {
+                self.pending.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+
+    /// The task which has been waiting the longest
+    pub fn oldest(&self) -> Option<Tid> {
+        self.pending.front().copied()
+    }
+
+    /// Pending requests in the order they arrived
+    pub fn iter(&self) -> impl Iterator<Item = Tid> + '_ {
+        self.pending.iter().copied()
+    }
+}
+
+pub fn request_channel(from: &mut Task, to: Tid) -> SyscallResult<(), KError> {
     let current_tid = CURRENT_TASK.get().unwrap();
 
     // Doesn't make sense to make a shared memory channel with itself and we'd
     // also end up deadlocking ourselves
     if current_tid == to {
-        return SyscallResult::Err(KError::InvalidArgument(0));
+        return SyscallResult::Err(KError::InvalidChannelArgument(0, ChannelArgumentError::SameTask));
     }
 
     let to_task = match TASKS.get(to) {
@@ -60,9 +529,11 @@ pub fn request_channel(from: &mut Task, to: Tid) -> SyscallResult<Message, KErro
     let mut to_task = to_task.lock();
 
     if to_task.state.is_dead() {
-        return SyscallResult::Err(KError::InvalidRecipient);
-    } else if !to_task.promiscuous {
-        return SyscallResult::Ok(KernelNotification::ChannelRequestDenied.into());
+        return SyscallResult::Err(KError::RecipientDead);
+    } else if !to_task.promiscuous && !to_task.channel_allowlist.contains(&current_tid) {
+        return SyscallResult::Err(KError::ConnectionRefused);
+    } else if !has_channel_room(from) {
+        return SyscallResult::Err(KError::ChannelLimitReached);
     }
 
     to_task.incoming_channel_request.insert(current_tid);
@@ -71,16 +542,48 @@ pub fn request_channel(from: &mut Task, to: Tid) -> SyscallResult<Message, KErro
     log::info!("blocking {:?}", current_tid);
     from.state = TaskState::Blocked;
 
-    SyscallResult::Ok(Message::default())
+    SyscallResult::Ok(())
+}
+
+/// Allow `tid` to request a channel with the task even when the task isn't
+/// accepting requests from everyone
+pub fn allow_channel_requests(task: &mut Task, tid: Tid) -> SyscallResult<(), KError> {
+    task.channel_allowlist.insert(tid);
+    SyscallResult::Ok(())
+}
+
+pub fn disallow_channel_requests(task: &mut Task, tid: Tid) -> SyscallResult<(), KError> {
+    task.channel_allowlist.remove(&tid);
+    SyscallResult::Ok(())
 }
 
-pub fn create_channel(from: &mut Task, to: Tid) -> SyscallResult<usize, KError> {
+/// Create a channel between the current task and `to`, where messages on both
+/// ends of the channel may be at most `max_message_size` bytes, or
+/// [`MAX_CHANNEL_BYTES`] if zero. If `credits` is non-zero, each end of the
+/// channel may only have that many messages sent and not yet retired. If
+/// `initial_message` is given, it's sent to `to` as the first message on the
+/// channel, and so is read before anything sent after the channel opens.
+pub fn create_channel(
+    from: &mut Task,
+    to: Tid,
+    max_message_size: usize,
+    credits: usize,
+    initial_message: Option<&[u8]>,
+) -> SyscallResult<usize, KError> {
     let current_tid = CURRENT_TASK.get().unwrap();
 
     // Doesn't make sense to make a shared memory channel with itself and we'd
     // also end up deadlocking ourselves
     if current_tid == to {
-        return SyscallResult::Err(KError::InvalidArgument(0));
+        return SyscallResult::Err(KError::InvalidChannelArgument(0, ChannelArgumentError::SameTask));
+    }
+
+    if let Some(message) = initial_message {
+        let too_large =
+            message.len() > MAX_INITIAL_MESSAGE_BYTES || (max_message_size != 0 && message.len() > max_message_size);
+        if too_large {
+            return SyscallResult::Err(KError::InvalidChannelArgument(3, ChannelArgumentError::MessageTooLarge));
+        }
     }
 
     let to_task = match TASKS.get(to) {
@@ -94,53 +597,214 @@ pub fn create_channel(from: &mut Task, to: Tid) -> SyscallResult<usize, KError>
         return SyscallResult::Err(KError::InvalidRecipient);
     }
 
-    let counter = Arc::new(AtomicUsize::new(0));
+    // Only allow opening a channel into a task that hasn't consented to it if
+    // it's accepting any and all channel requests
+    if !to_task.promiscuous && !from.incoming_channel_request.contains(&to) {
+        return SyscallResult::Err(KError::InvalidOperation);
+    }
 
-    let from_channel_id = ChannelId::new(from.channels.last_key_value().map(|(id, _)| id.value() + 1).unwrap_or(0));
-    let to_channel_id = ChannelId::new(to_task.channels.last_key_value().map(|(id, _)| id.value() + 1).unwrap_or(0));
+    if !has_channel_room(from) || !has_channel_room(&to_task) {
+        return SyscallResult::Err(KError::ChannelLimitReached);
+    }
 
-    let from_channel = UserspaceChannel {
-        other_task: to,
-        other_channel_id: to_channel_id,
-        message_id_counter: counter.clone(),
-        write_regions: BTreeMap::new(),
-        read_regions: BTreeMap::new(),
-    };
+    let (from_channel_id, to_channel_id) =
+        connect_channel(from, current_tid, &mut to_task, to, max_message_size, credits);
 
-    let to_channel = UserspaceChannel {
-        other_task: current_tid,
-        other_channel_id: from_channel_id,
-        message_id_counter: counter,
-        write_regions: BTreeMap::new(),
-        read_regions: BTreeMap::new(),
-    };
+    if let Some(message) = initial_message {
+        let channel = from.channels.get_mut(&from_channel_id).unwrap();
+        seed_message(channel, &mut to_task, to_channel_id, message);
+    }
 
     if from.incoming_channel_request.remove(&to) {
         log::info!("unblocking {:?}", to);
         to_task.state = TaskState::Running;
+        scheduler::wake_idle_harts();
     }
 
-    from.channels.insert(from_channel_id, from_channel);
-    to_task.channels.insert(to_channel_id, to_channel);
-
     to_task.message_queue.push_front((Sender::kernel(), KernelNotification::ChannelOpened(to_channel_id).into()));
 
     SyscallResult::Ok(from_channel_id.value())
 }
 
+/// Create a privileged, pre-connected channel between `task_a` and `task_b`,
+/// both of which must have been spawned by the current task, without either
+/// of them having to request or accept it. Both tasks are notified of their
+/// end of the channel, and the IDs of the `task_a` and `task_b` ends are
+/// returned so the parent can pass them along if it needs to.
+pub fn create_channel_pair(
+    parent: &mut Task,
+    task_a: Tid,
+    task_b: Tid,
+    max_message_size: usize,
+    credits: usize,
+) -> SyscallResult<(usize, usize), KError> {
+    let current_tid = CURRENT_TASK.get().unwrap();
+
+    if task_a == task_b {
+        return SyscallResult::Err(KError::InvalidChannelArgument(1, ChannelArgumentError::SameTask));
+    }
+
+    let (a, b) = match (TASKS.get(task_a), TASKS.get(task_b)) {
+        (Some(a), Some(b)) => (a, b),
+        _ => return SyscallResult::Err(KError::InvalidRecipient),
+    };
+
+    // A task's parent never changes, so this is checked one task at a time
+    // before holding both locks, which means only the parent can ever be
+    // waiting on both. The current task can't be a child of itself, so this
+    // also rules out trying to lock it a second time below.
+    let a_is_child = a.lock().parent == Some(current_tid);
+    let b_is_child = b.lock().parent == Some(current_tid);
+    if !a_is_child || !b_is_child {
+        return SyscallResult::Err(KError::InvalidOperation);
+    }
+
+    // Always lock the tasks in the same order, same as `splice_channels`
+    let (mut a, mut b) = match task_a < task_b {
+        true => {
+            let a = a.lock();
+            (a, b.lock())
+        }
+        false => {
+            let b = b.lock();
+            (a.lock(), b)
+        }
+    };
+
+    if a.state.is_dead() || b.state.is_dead() {
+        return SyscallResult::Err(KError::InvalidRecipient);
+    } else if !has_channel_room(&a) || !has_channel_room(&b) {
+        return SyscallResult::Err(KError::ChannelLimitReached);
+    }
+
+    let (a_channel_id, b_channel_id) = connect_channel(&mut a, task_a, &mut b, task_b, max_message_size, credits);
+
+    a.message_queue.push_front((Sender::kernel(), KernelNotification::ChannelOpened(a_channel_id).into()));
+    b.message_queue.push_front((Sender::kernel(), KernelNotification::ChannelOpened(b_channel_id).into()));
+
+    log::debug!("[{}:{}] Connected {:?} and {:?}", parent.name, current_tid, task_a, task_b);
+
+    SyscallResult::Ok((a_channel_id.value(), b_channel_id.value()))
+}
+
+/// Insert both ends of a new channel between `a` and `b` into their channel
+/// maps, returning the ID of the end belonging to each. `max_message_size`
+/// and `credits` are the raw syscall arguments, where zero means the default.
+fn connect_channel(
+    a: &mut Task,
+    a_tid: Tid,
+    b: &mut Task,
+    b_tid: Tid,
+    max_message_size: usize,
+    credits: usize,
+) -> (ChannelId, ChannelId) {
+    let max_message_size = match max_message_size {
+        0 => MAX_CHANNEL_BYTES,
+        n => n,
+    };
+
+    let credits = match credits {
+        0 => None,
+        n => Some(n),
+    };
+
+    // Message IDs start at 1 so that 0 can be used to mean "no message", e.g.
+    // for messages which aren't a reply to anything
+    let counter = Arc::new(AtomicUsize::new(1));
+
+    // Channel IDs are never reused, even after the channel is closed, so a
+    // stale ID can't accidentally refer to an unrelated channel
+    let a_channel_id = ChannelId::new(a.channel_next_id);
+    a.channel_next_id += 1;
+    let b_channel_id = ChannelId::new(b.channel_next_id);
+    b.channel_next_id += 1;
+
+    let channel_rights = CapabilityRights::READ | CapabilityRights::WRITE;
+    let a_capability =
+        a.cspace.mint(Capability { resource: CapabilityResource::Channel(a_channel_id), rights: channel_rights });
+    let b_capability =
+        b.cspace.mint(Capability { resource: CapabilityResource::Channel(b_channel_id), rights: channel_rights });
+
+    let a_channel =
+        UserspaceChannel::new(b_tid, b_channel_id, a_capability, max_message_size, credits, counter.clone());
+
+    let b_channel = UserspaceChannel::new(a_tid, a_channel_id, b_capability, max_message_size, credits, counter);
+
+    a.channels.insert(a_channel_id, a_channel);
+    b.channels.insert(b_channel_id, b_channel);
+
+    (a_channel_id, b_channel_id)
+}
+
+/// Deliver `message` to the `to_channel_id` end of a freshly connected channel
+/// as if it had been created and sent from `channel`. Since nothing else has
+/// been sent yet, it gets the lowest message ID and sequence number on the
+/// channel.
+fn seed_message(channel: &mut UserspaceChannel, to: &mut Task, to_channel_id: ChannelId, message: &[u8]) {
+    // The page is zeroed first so whatever was in the rest of it before isn't
+    // leaked to the receiver
+    let mut data = alloc::vec![0; PageSize::Kilopage.to_byte_size()];
+    data[..message.len()].copy_from_slice(message);
+
+    let message_id = MessageId::new(channel.next_message_id());
+    let (region, _) = to.memory_manager.alloc_shared_region(
+        None,
+        RegionDescription {
+            size: PageSize::Kilopage,
+            len: 1,
+            contiguous: false,
+            flags: flags::READ | flags::WRITE | flags::USER | flags::VALID,
+            fill: FillOption::Data(&data),
+            kind: AddressRegionKind::Channel,
+        },
+    );
+
+    to.shared_memory_bytes += PageSize::Kilopage.to_byte_size();
+
+    channel.messages_created += 1;
+    let sequence = channel.record_send(message.len());
+
+    let to_channel = to.channels.get_mut(&to_channel_id).unwrap();
+    let sender = to_channel.other_task;
+    to_channel
+        .receive(message_id, ReceivedMessage { region, len: message.len(), reply_to: None, sequence, tag: 0, sender });
+}
+
+/// Create a message on the channel with room for at least `size` bytes. All of
+/// the message's pages are allocated, zeroed, and mapped before this returns
+/// rather than on first touch, so writing to the message never faults.
 // FIXME: Definitely should be a way to return tuple values that can be
 // converted into `usize` so its a lot more clear what's what
-pub fn create_message(task: &mut Task, channel_id: usize, size: usize) -> SyscallResult<(usize, usize, usize), KError> {
+pub fn create_message(
+    task: &mut Task,
+    channel_id: usize,
+    size: usize,
+) -> SyscallResult<(usize, usize, usize, usize), KError> {
     let channel_id = ChannelId::new(channel_id);
     let channel = match task.channels.get_mut(&channel_id) {
         Some(channel) => channel,
-        None => return SyscallResult::Err(KError::InvalidArgument(0)),
+        None => return SyscallResult::Err(KError::InvalidChannelArgument(0, ChannelArgumentError::UnknownChannel)),
+    };
+
+    if size > channel.max_message_size {
+        return SyscallResult::Err(KError::InvalidChannelArgument(1, ChannelArgumentError::MessageTooLarge));
+    }
+
+    // The channel's maximum size can be as large as `usize::MAX`, and a size
+    // that close to it would wrap around to a tiny region when rounded up
+    let n_pages = match PageSize::Kilopage.checked_pages_for(size) {
+        Some(n_pages) => n_pages,
+        None => return SyscallResult::Err(KError::InvalidArgument(1)),
     };
+    let capacity = n_pages * PageSize::Kilopage.to_byte_size();
 
-    let n_pages = utils::round_up_to_next(size, 4.kib()) / 4.kib();
+    match task.shared_memory_bytes.checked_add(capacity) {
+        Some(total) if total <= task.shared_memory_quota => {}
+        _ => return SyscallResult::Err(KError::QuotaExceeded),
+    }
 
     let message_id = channel.next_message_id();
-    let (region, _) = task.memory_manager.alloc_shared_region(
+    let (region, backing) = task.memory_manager.alloc_shared_region(
         None,
         RegionDescription {
             size: PageSize::Kilopage,
@@ -152,76 +816,1652 @@ pub fn create_message(task: &mut Task, channel_id: usize, size: usize) -> Syscal
         },
     );
 
-    let size = n_pages * 4.kib();
+    task.shared_memory_bytes += capacity;
+    channel.write_regions.insert(MessageId::new(message_id), OutgoingMessage { region: region.clone(), backing });
+    channel.messages_created += 1;
 
-    channel.write_regions.insert(MessageId::new(message_id), region.clone());
+    SyscallResult::Ok((message_id, region.start.as_usize(), size, capacity))
+}
 
-    SyscallResult::Ok((message_id, region.start.as_usize(), size))
+/// Only the memory minted by [`create_message`] may be handed to another task,
+/// anything else mapped at the same address could be memory the sender has no
+/// business sharing. This is checked before tearing anything down so a bad
+/// region leaves the message intact.
+fn validate_minted_backing(
+    task: &Task,
+    channel_id: ChannelId,
+    message_id: MessageId,
+    range: &Range<VirtualAddress>,
+) -> SyscallResult<(), KError> {
+    let minted = &task.channels[&channel_id].write_regions[&message_id].backing;
+    match task.memory_manager.region_for(range.start) {
+        Some(AddressRegion {
+            region: Some(MemoryRegion::Backed(PhysicalRegion::Shared(backing))),
+            kind: AddressRegionKind::Channel,
+            ..
+        }) if backing.is_same_backing(minted) => SyscallResult::Ok(()),
+        region => {
+            log::error!(
+                "[{}:{}] Message {:?} on channel {:?} isn't backed by the memory it was created with: {:?}",
+                task.name,
+                CURRENT_TASK.get().unwrap(),
+                message_id,
+                channel_id,
+                region,
+            );
+            SyscallResult::Err(KError::InvalidChannelArgument(1, ChannelArgumentError::InvalidRegion))
+        }
+    }
 }
 
-pub fn send_message(task: &mut Task, channel_id: usize, message_id: usize, len: usize) -> SyscallResult<(), KError> {
+/// Send a message to the other end of the channel, optionally marking it as a
+/// reply to a message with ID `reply_to` (or 0 if it isn't a reply) so that
+/// the receiver can match up replies with their requests. `tag` is handed to
+/// the receiver as-is alongside the message, so it can dispatch on it without
+/// reserving part of the payload for a header. Once the receiver reads the
+/// message, it's guaranteed to observe every write the sender made to the
+/// message before calling `send_message`. If the receiver has no room for the
+/// message, it stays mapped in the sender and [`KError::RecipientFull`] is
+/// returned.
+pub fn send_message(
+    task: &mut Task,
+    channel_id: usize,
+    message_id: usize,
+    len: usize,
+    reply_to: usize,
+    tag: u64,
+) -> SyscallResult<(), KError> {
     let channel_id = ChannelId::new(channel_id);
-    let channel = match task.channels.get_mut(&channel_id) {
-        Some(channel) => channel,
-        None => return SyscallResult::Err(KError::InvalidArgument(0)),
+    let message_id = MessageId::new(message_id);
+    let reply_to = match reply_to {
+        0 => None,
+        id => Some(MessageId::new(id)),
     };
+    let range = validate_message_region(task, channel_id, message_id, MessageDirection::Outgoing)?;
 
-    let range = match channel.write_regions.remove(&MessageId::new(message_id)) {
-        Some(range) => range,
-        None => return SyscallResult::Err(KError::InvalidArgument(1)),
+    if range.end.as_usize() - range.start.as_usize() < len {
+        return SyscallResult::Err(KError::InvalidChannelArgument(2, ChannelArgumentError::LengthExceedsRegion));
+    }
+
+    validate_minted_backing(task, channel_id, message_id, &range)?;
+
+    let channel = task.channels.get_mut(&channel_id).unwrap();
+    if channel.credits == Some(0) {
+        return SyscallResult::Err(KError::ChannelFull);
+    }
+
+    let other = match TASKS.get(channel.other_task) {
+        Some(other) => other,
+        None => return SyscallResult::Err(KError::InvalidRecipient),
     };
+    let mut other = other.lock();
 
-    if range.end.as_usize() - range.start.as_usize() < len {
-        return SyscallResult::Err(KError::InvalidArgument(2));
+    if !peer_channel_open(&other, channel) {
+        return SyscallResult::Err(KError::InvalidRecipient);
+    }
+
+    let size = range.end.as_usize() - range.start.as_usize();
+    if other.shared_memory_bytes + size > other.shared_memory_quota {
+        return SyscallResult::Err(KError::RecipientFull);
     }
 
+    let message = channel.write_regions.remove(&message_id).unwrap();
+
     let backing = match task.memory_manager.dealloc_region(range.start) {
         MemoryRegion::Backed(PhysicalRegion::Shared(phys_region)) => phys_region,
-        _ => unreachable!(),
+        _ => unreachable!("backing was checked to be the minted shared memory above"),
     };
 
-    let other = TASKS.get(channel.other_task).unwrap();
-    let mut other = other.lock();
+    // The sender may have filled in the message on a different hart than the
+    // one the receiver will read it on, so make sure its writes are visible
+    // before the receiver can get at the region
+    mem::fence();
 
-    let region = other.memory_manager.apply_shared_region(
+    let region = match other.memory_manager.apply_shared_region(
         None,
         flags::READ | flags::WRITE | flags::USER | flags::VALID,
         backing,
         AddressRegionKind::Channel,
-    );
+    ) {
+        Ok(region) => region,
+        // The receiver has run out of address space, so give the message back
+        // to the sender where it was so it can try again later
+        Err(backing) => {
+            task.memory_manager
+                .apply_shared_region(
+                    Some(range.start),
+                    flags::READ | flags::WRITE | flags::USER | flags::VALID,
+                    backing,
+                    AddressRegionKind::Channel,
+                )
+                .expect("message region was just unmapped from the sender");
+            channel.write_regions.insert(message_id, message);
+
+            return SyscallResult::Err(KError::RecipientFull);
+        }
+    };
+
+    // The backing memory now belongs to the receiver
+    task.shared_memory_bytes -= size;
+    other.shared_memory_bytes += size;
 
     let other_channel = other.channels.get_mut(&channel.other_channel_id).unwrap();
-    other_channel.read_regions.insert(MessageId::new(message_id), (region, len));
+    let sequence = channel.record_send(len);
+    let sender = other_channel.other_task;
+    other_channel.receive(message_id, ReceivedMessage { region, len, reply_to, sequence, tag, sender });
+    wake_receiver(&mut other);
 
     SyscallResult::Ok(())
 }
 
-pub fn read_message(task: &mut Task, channel_id: usize) -> SyscallResult<(usize, usize, usize), KError> {
+/// Same as [`send_message`], but if the channel is out of credits the task is
+/// blocked until the receiver retires a message, and [`KError::ChannelFull`]
+/// is returned so that the send can be tried again once it's woken. If the
+/// other end of the channel has closed, [`KError::ChannelClosed`] is returned
+/// instead, including to a sender which was blocked when it closed.
+pub fn send_message_blocking(
+    task: &mut Task,
+    channel_id: usize,
+    message_id: usize,
+    len: usize,
+    reply_to: usize,
+    tag: u64,
+) -> SyscallResult<(), KError> {
     let id = ChannelId::new(channel_id);
-    let channel = match task.channels.get_mut(&id) {
-        Some(channel) => channel,
-        None => return SyscallResult::Err(KError::InvalidArgument(0)),
+    validate_message_region(task, id, MessageId::new(message_id), MessageDirection::Outgoing)?;
+
+    let channel = task.channels.get_mut(&id).unwrap();
+    let peer_open = match TASKS.get(channel.other_task) {
+        Some(other) => peer_channel_open(&other.lock(), channel),
+        None => false,
     };
 
-    // TODO: need to be able to return more than just the first one
-    match channel.read_regions.iter().next() {
-        Some((id, (region, len))) => SyscallResult::Ok((id.value(), region.start.as_usize(), *len)),
-        None => SyscallResult::Ok((0, 0, 0)),
+    if !peer_open {
+        return SyscallResult::Err(KError::ChannelClosed);
     }
+
+    if channel.credits == Some(0) {
+        channel.sender_blocked = true;
+        task.state = TaskState::Blocked;
+        return SyscallResult::Err(KError::ChannelFull);
+    }
+
+    send_message(task, channel_id, message_id, len, reply_to, tag)
 }
 
-pub fn retire_message(task: &mut Task, channel_id: usize, message_id: usize) -> SyscallResult<(), KError> {
-    let id = ChannelId::new(channel_id);
-    let channel = match task.channels.get_mut(&id) {
-        Some(channel) => channel,
-        None => return SyscallResult::Err(KError::InvalidArgument(0)),
+/// Send one message to the other end of `channel_id` and of every channel in
+/// `also_to` at once, without copying it. Every receiver gets the same memory
+/// mapped read-only, which is only freed once the last of them retires it.
+/// Receivers on the other channels see the message under a fresh ID from that
+/// channel. If one of the peers dies partway through, the others still
+/// receive the message.
+pub fn send_message_shared(
+    task: &mut Task,
+    channel_id: usize,
+    message_id: usize,
+    len: usize,
+    also_to: &[usize],
+) -> SyscallResult<(), KError> {
+    let channel_id = ChannelId::new(channel_id);
+    let message_id = MessageId::new(message_id);
+    let range = validate_message_region(task, channel_id, message_id, MessageDirection::Outgoing)?;
+    let size = range.end.as_usize() - range.start.as_usize();
+
+    if size < len {
+        return SyscallResult::Err(KError::InvalidChannelArgument(2, ChannelArgumentError::LengthExceedsRegion));
+    }
+
+    let targets: Vec<ChannelId> =
+        core::iter::once(channel_id).chain(also_to.iter().copied().map(ChannelId::new)).collect();
+    for (i, target) in targets.iter().enumerate() {
+        let channel = match task.channels.get(target) {
+            Some(channel) if !targets[..i].contains(target) => channel,
+            _ => {
+                return SyscallResult::Err(KError::InvalidChannelArgument(3 + i, ChannelArgumentError::UnknownChannel))
+            }
+        };
+
+        if channel.credits == Some(0) {
+            return SyscallResult::Err(KError::ChannelFull);
+        }
+
+        // Each peer is locked one at a time since several of the channels
+        // could lead to the same task
+        match TASKS.get(channel.other_task) {
+            Some(other) if peer_channel_open(&other.lock(), channel) => {}
+            _ => return SyscallResult::Err(KError::InvalidRecipient),
+        }
+    }
+
+    validate_minted_backing(task, channel_id, message_id, &range)?;
+
+    task.channels.get_mut(&channel_id).unwrap().write_regions.remove(&message_id);
+    let backing = match task.memory_manager.dealloc_region(range.start) {
+        MemoryRegion::Backed(PhysicalRegion::Shared(phys_region)) => phys_region,
+        _ => unreachable!("backing was checked to be the minted shared memory above"),
     };
+    task.shared_memory_bytes -= size;
 
-    match channel.read_regions.remove(&MessageId::new(message_id)) {
-        Some(region) => {
-            task.memory_manager.dealloc_region(region.0.start);
-            SyscallResult::Ok(())
+    mem::fence();
+
+    for target in targets {
+        let channel = task.channels.get_mut(&target).unwrap();
+        let other = match TASKS.get(channel.other_task) {
+            Some(other) => other,
+            None => continue,
+        };
+        let mut other = other.lock();
+
+        if !peer_channel_open(&other, channel) {
+            continue;
         }
-        None => SyscallResult::Err(KError::InvalidArgument(1)),
+
+        let received_id = match target == channel_id {
+            true => message_id,
+            false => MessageId::new(channel.next_message_id()),
+        };
+
+        // Nobody gets to write to the message now that there's more than one
+        // reader of it
+        let region = match other.memory_manager.apply_shared_region(
+            None,
+            flags::READ | flags::USER | flags::VALID,
+            backing.clone(),
+            AddressRegionKind::Channel,
+        ) {
+            Ok(region) => region,
+            // Same as a peer dying partway through, the others still get it
+            Err(_) => {
+                log::warn!("[{}] Dropping shared message to {:?}, it's out of address space", task.name, other.name);
+                continue;
+            }
+        };
+        other.shared_memory_bytes += size;
+
+        let sequence = channel.record_send(len);
+        let other_channel = other.channels.get_mut(&channel.other_channel_id).unwrap();
+        let sender = other_channel.other_task;
+        other_channel.receive(received_id, ReceivedMessage { region, len, reply_to: None, sequence, tag: 0, sender });
+        wake_receiver(&mut other);
+    }
+
+    SyscallResult::Ok(())
+}
+
+/// Transfer ownership of an existing memory region, which was not necessarily
+/// created with [`create_message`], to the other side of the channel
+pub fn send_region(
+    task: &mut Task,
+    channel_id: usize,
+    region_start: VirtualAddress,
+    len: usize,
+) -> SyscallResult<usize, KError> {
+    let (message_id, _) = give_region(task, ChannelId::new(channel_id), region_start, len, AddressRegionKind::Channel)?;
+    SyscallResult::Ok(message_id.value())
+}
+
+/// A region sent with [`lend_region`] which can be taken back from the task
+/// it was sent to with [`recall_region`]
+pub struct LentRegion {
+    borrower: Tid,
+    borrower_channel_id: ChannelId,
+    /// Where the region is mapped in the borrower
+    region: Range<VirtualAddress>,
+    backing: SharedPhysicalRegion,
+    /// What the region was in the lender before it was lent out
+    kind: AddressRegionKind,
+    /// How the region was mapped in the lender before it was lent out
+    flags: Flags,
+}
+
+/// Same as [`send_region`], except the task keeps the right to take the region
+/// back with [`recall_region`] at any point, whether or not the other side has
+/// retired it. The region can't be forwarded on by the borrower.
+pub fn lend_region(
+    task: &mut Task,
+    channel_id: usize,
+    region_start: VirtualAddress,
+    len: usize,
+) -> SyscallResult<usize, KError> {
+    let channel_id = ChannelId::new(channel_id);
+
+    // Channel regions count towards the quota of whoever has them mapped,
+    // which would get murky once they can be recalled
+    if let Some(AddressRegion { kind: AddressRegionKind::Channel, .. }) = task.memory_manager.region_for(region_start) {
+        return SyscallResult::Err(KError::InvalidChannelArgument(1, ChannelArgumentError::InvalidRegion));
+    }
+
+    let (message_id, lent) = give_region(task, channel_id, region_start, len, AddressRegionKind::Borrowed)?;
+    task.lent_regions.insert((channel_id, message_id), lent);
+
+    SyscallResult::Ok(message_id.value())
+}
+
+/// Take back a region lent out with [`lend_region`] as message `message_id`
+/// on `channel_id`, unmapping it from the borrower and mapping it back into
+/// the task. The borrower faults like it would for any other unmapped memory
+/// if it touches the region afterwards. Returns the address the region is now
+/// mapped at, or [`KError::AddressSpaceExhausted`] with the region left lent
+/// out if there's nowhere to map it.
+pub fn recall_region(task: &mut Task, channel_id: usize, message_id: usize) -> SyscallResult<usize, KError> {
+    let lent = match task.lent_regions.remove(&(ChannelId::new(channel_id), MessageId::new(message_id))) {
+        Some(lent) => lent,
+        None => return SyscallResult::Err(KError::InvalidChannelArgument(1, ChannelArgumentError::UnknownMessage)),
+    };
+
+    // Make sure there's somewhere to put the region back before taking it
+    // away from the borrower, so the recall can be retried later instead
+    let at = match task.memory_manager.try_find_free_region(lent.backing.page_size(), lent.backing.n_pages()) {
+        Some(at) => at,
+        None => {
+            task.lent_regions.insert((ChannelId::new(channel_id), MessageId::new(message_id)), lent);
+            return SyscallResult::Err(KError::AddressSpaceExhausted);
+        }
+    };
+
+    // The borrower may have retired the message or died since, in which case
+    // the region is no longer mapped there and there's nothing to undo
+    if let Some(borrower) = TASKS.get(lent.borrower) {
+        let mut borrower = borrower.lock();
+        let still_mapped = match borrower.memory_manager.region_for(lent.region.start) {
+            Some(AddressRegion {
+                region: Some(MemoryRegion::Backed(PhysicalRegion::Shared(backing))), span, ..
+            }) => *span == lent.region && backing.is_same_backing(&lent.backing),
+            _ => false,
+        };
+
+        if still_mapped {
+            borrower.memory_manager.dealloc_region(lent.region.start);
+            borrower.shared_memory_bytes -= lent.region.end.as_usize() - lent.region.start.as_usize();
+
+            if let Some(channel) = borrower.channels.get_mut(&lent.borrower_channel_id) {
+                channel.read_regions.remove(&MessageId::new(message_id));
+            }
+        }
+    }
+
+    let region = task
+        .memory_manager
+        .apply_shared_region(Some(at), lent.flags, lent.backing, lent.kind)
+        .expect("free space was found before unmapping from the borrower");
+
+    SyscallResult::Ok(region.start.as_usize())
+}
+
+/// Unmap the region at `region_start` from the task and map it into the other
+/// end of the channel as a message, with the region mapped as `peer_kind`
+fn give_region(
+    task: &mut Task,
+    channel_id: ChannelId,
+    region_start: VirtualAddress,
+    len: usize,
+    peer_kind: AddressRegionKind,
+) -> SyscallResult<(MessageId, LentRegion), KError> {
+    let channel = match task.channels.get_mut(&channel_id) {
+        Some(channel) => channel,
+        None => return SyscallResult::Err(KError::InvalidChannelArgument(0, ChannelArgumentError::UnknownChannel)),
+    };
+
+    if channel.credits == Some(0) {
+        return SyscallResult::Err(KError::ChannelFull);
+    }
+
+    // The receiver only agreed to messages up to the channel's maximum size,
+    // which sending an existing region mustn't be a way around
+    if len > channel.max_message_size {
+        return SyscallResult::Err(KError::InvalidChannelArgument(2, ChannelArgumentError::MessageTooLarge));
+    }
+
+    // Channel regions count towards the shared memory quota, other regions
+    // only start counting once they've been sent
+    let (size, kind) = match task.memory_manager.region_for(region_start) {
+        Some(AddressRegion { region: Some(MemoryRegion::Backed(_)), span, kind })
+            if span.start == region_start && span.end.as_usize() - span.start.as_usize() >= len =>
+        {
+            let size = span.end.as_usize() - span.start.as_usize();
+
+            // Only allow regions that the task explicitly allocated for itself,
+            // giving away things like the stack or program text is never what
+            // was intended
+            match kind {
+                AddressRegionKind::UserAllocated | AddressRegionKind::Dma | AddressRegionKind::Channel => (size, *kind),
+                _ => return SyscallResult::Err(KError::InvalidChannelArgument(1, ChannelArgumentError::InvalidRegion)),
+            }
+        }
+        Some(AddressRegion { region: Some(MemoryRegion::Backed(_)), span, .. }) if span.start == region_start => {
+            return SyscallResult::Err(KError::InvalidChannelArgument(2, ChannelArgumentError::LengthExceedsRegion))
+        }
+        _ => return SyscallResult::Err(KError::InvalidChannelArgument(1, ChannelArgumentError::InvalidRegion)),
+    };
+
+    let other = match TASKS.get(channel.other_task) {
+        Some(other) => other,
+        None => return SyscallResult::Err(KError::InvalidRecipient),
+    };
+    let mut other = other.lock();
+
+    if !peer_channel_open(&other, channel) {
+        return SyscallResult::Err(KError::InvalidRecipient);
+    }
+
+    if other.shared_memory_bytes + size > other.shared_memory_quota {
+        return SyscallResult::Err(KError::RecipientFull);
+    }
+
+    let region_flags = task.memory_manager.page_flags(region_start).unwrap();
+    let backing = match task.memory_manager.dealloc_region(region_start) {
+        MemoryRegion::Backed(PhysicalRegion::Shared(phys_region)) => phys_region,
+        MemoryRegion::Backed(PhysicalRegion::Unique(phys_region)) => phys_region.into_shared_region(),
+        _ => return SyscallResult::Err(KError::InvalidChannelArgument(1, ChannelArgumentError::InvalidRegion)),
+    };
+
+    // Same ordering guarantee as `send_message`
+    mem::fence();
+
+    let region = match other.memory_manager.apply_shared_region(
+        None,
+        peer_region_flags(region_flags),
+        backing.clone(),
+        peer_kind,
+    ) {
+        Ok(region) => region,
+        Err(_) => {
+            task.memory_manager
+                .apply_shared_region(Some(region_start), region_flags, backing, kind)
+                .expect("region was just unmapped from the sender");
+
+            return SyscallResult::Err(KError::RecipientFull);
+        }
+    };
+
+    if kind == AddressRegionKind::Channel {
+        task.shared_memory_bytes -= size;
+    }
+    other.shared_memory_bytes += size;
+
+    let message_id = MessageId::new(channel.next_message_id());
+
+    let other_channel = other.channels.get_mut(&channel.other_channel_id).unwrap();
+    let sequence = channel.record_send(len);
+    let sender = other_channel.other_task;
+    let message = ReceivedMessage { region: region.clone(), len, reply_to: None, sequence, tag: 0, sender };
+    other_channel.receive(message_id, message);
+    wake_receiver(&mut other);
+
+    let lent = LentRegion {
+        borrower: channel.other_task,
+        borrower_channel_id: channel.other_channel_id,
+        region,
+        backing,
+        kind,
+        flags: region_flags,
+    };
+
+    drop(other);
+
+    // Channel regions are still tracked by their message ID on whichever
+    // channel they were created or received on, so make sure we don't leave a
+    // dangling entry behind
+    if let Some((sender, sender_channel_id)) = forget_message_region(task, region_start) {
+        return_credit(sender, sender_channel_id);
+    }
+
+    SyscallResult::Ok((message_id, lent))
+}
+
+/// The flags a region mapped with `region_flags` is given when it's mapped
+/// into another task, which can never be more than the task giving it away
+/// had. In particular, a read-only message can't be made writable by passing
+/// it on.
+fn peer_region_flags(region_flags: Flags) -> Flags {
+    match region_flags & flags::WRITE {
+        true => flags::READ | flags::WRITE | flags::USER | flags::VALID,
+        false => flags::READ | flags::USER | flags::VALID,
+    }
+}
+
+/// Remove the message backed by the region at `region_start` from whichever of
+/// the task's channels it's pending on, now that the region has been given
+/// away. A received message passed on this way counts as retired, so the task
+/// and channel that sent it are returned for it to be given its credit back.
+fn forget_message_region(task: &mut Task, region_start: VirtualAddress) -> Option<(Tid, ChannelId)> {
+    for channel in task.channels.values_mut() {
+        channel.write_regions.retain(|_, message| message.region.start != region_start);
+
+        let received = channel.read_regions.iter().find(|(_, message)| message.region.start == region_start);
+        if let Some((&message_id, _)) = received {
+            channel.read_regions.remove(&message_id);
+            channel.messages_retired += 1;
+
+            return Some((channel.other_task, channel.other_channel_id));
+        }
+    }
+
+    None
+}
+
+pub fn read_message(
+    task: &mut Task,
+    channel_id: usize,
+) -> SyscallResult<(usize, usize, usize, usize, usize, usize, usize, usize, usize), KError> {
+    let id = ChannelId::new(channel_id);
+    let channel = match task.channels.get_mut(&id) {
+        Some(channel) => channel,
+        None => return SyscallResult::Err(KError::InvalidChannelArgument(0, ChannelArgumentError::UnknownChannel)),
+    };
+
+    if !task.cspace.grants_channel(channel.capability, id, CapabilityRights::READ) {
+        return SyscallResult::Err(KError::InvalidCapability);
+    }
+
+    // TODO: need to be able to return more than just the first one
+    match channel.read_regions.iter().next() {
+        Some((id, message)) => {
+            channel.messages_read += 1;
+            let access = match task.memory_manager.page_flags(message.region.start) {
+                Some(page_flags) if page_flags & flags::WRITE => MessageAccess::ReadWrite,
+                _ => MessageAccess::ReadOnly,
+            };
+
+            SyscallResult::Ok(message.to_parts(*id, access))
+        }
+        // Messages that arrived before the other end went away can still be
+        // read, but after that there's no point in waiting for any more
+        None if channel.peer_closed => SyscallResult::Err(KError::ChannelClosed),
+        None => match TASKS.get(channel.other_task) {
+            Some(other) if peer_channel_open(&other.lock(), channel) => SyscallResult::Ok((0, 0, 0, 0, 0, 0, 0, 0, 0)),
+            _ => SyscallResult::Err(KError::ChannelClosed),
+        },
+    }
+}
+
+/// Read the first message waiting on any of the task's channels, along with
+/// the ID of the channel it arrived on. If none of the channels have a message
+/// waiting, but one of them has been closed by the other end, its ID is
+/// returned without a message so the task can close it. Otherwise the task is
+/// blocked until one of those happens and [`KError::NoMessages`] is returned,
+/// so the call should be made again once the task is woken.
+pub fn receive_any(
+    task: &mut Task,
+) -> SyscallResult<(usize, usize, usize, usize, usize, usize, usize, usize, usize, usize), KError> {
+    let channel_id = match first_ready_channel(&task.channels) {
+        Some(channel_id) => channel_id,
+        None => match task.channels.iter().find(|(_, channel)| channel.peer_closed) {
+            Some((&channel_id, _)) => {
+                let handle = open_handle(task, channel_id);
+                return SyscallResult::Ok((handle.value(), 0, 0, 0, 0, 0, 0, 0, 0, 0));
+            }
+            None => {
+                task.waiting_for_message = true;
+                task.state = TaskState::Blocked;
+                return SyscallResult::Err(KError::NoMessages);
+            }
+        },
+    };
+
+    let (message_id, ptr, len, capacity, access, reply_to, sequence, tag, sender) =
+        read_message(task, channel_id.value())?;
+    let handle = open_handle(task, channel_id);
+
+    SyscallResult::Ok((handle.value(), message_id, ptr, len, capacity, access, reply_to, sequence, tag, sender))
+}
+
+/// The first channel with a message which hasn't been read yet
+fn first_ready_channel(channels: &BTreeMap<ChannelId, UserspaceChannel>) -> Option<ChannelId> {
+    channels.iter().find(|(_, channel)| !channel.read_regions.is_empty()).map(|(id, _)| *id)
+}
+
+/// Copy a `T` out of the start of a message received on `channel_id` without
+/// handing out a pointer into the message. Returns `None` if the message
+/// doesn't exist, is shorter than a `T`, or isn't aligned for one.
+pub fn read_message_header<T: MessageHeader>(task: &Task, channel_id: ChannelId, message_id: MessageId) -> Option<T> {
+    let region = match validate_message_region(task, channel_id, message_id, MessageDirection::Incoming) {
+        SyscallResult::Ok(region) => region,
+        SyscallResult::Err(_) => return None,
+    };
+
+    let len = task.channels.get(&channel_id)?.read_regions.get(&message_id)?.len;
+
+    // Message pages aren't necessarily physically contiguous, so only the
+    // first one can be read through its physical address
+    let len = len.min(PageSize::Kilopage.to_byte_size());
+    let start = mem::phys2virt(task.memory_manager.resolve(region.start)?);
+    let bytes = unsafe { core::slice::from_raw_parts(start.as_ptr(), len) };
+
+    header_from_bytes(bytes)
+}
+
+/// Types which can be copied out of the start of a message with
+/// [`read_message_header`]
+///
+/// # Safety
+///
+/// The sender controls every byte of a message, so every bit pattern of
+/// `size_of::<Self>()` bytes must be a valid `Self`, which rules out `bool`,
+/// enums, references, and anything with padding.
+pub unsafe trait MessageHeader: Copy {}
+
+/// Copy a `T` out of the start of `bytes`, which must be at least as long as a
+/// `T` and aligned for one
+fn header_from_bytes<T: MessageHeader>(bytes: &[u8]) -> Option<T> {
+    if bytes.len() < core::mem::size_of::<T>() || bytes.as_ptr() as usize % core::mem::align_of::<T>() != 0 {
+        return None;
+    }
+
+    // SAFETY: the length and alignment were checked above, and `T:
+    // MessageHeader` means any bytes are a valid `T`
+    Some(unsafe { bytes.as_ptr().cast::<T>().read() })
+}
+
+pub fn retire_message(task: &mut Task, channel_id: usize, message_id: usize) -> SyscallResult<(), KError> {
+    let id = ChannelId::new(channel_id);
+    let message_id = MessageId::new(message_id);
+    let region = check_retirable(task, id, message_id)?;
+    retire_checked(task, id, message_id, region);
+
+    SyscallResult::Ok(())
+}
+
+/// Make sure the task can retire `message_id`, returning the region backing
+/// it if so
+fn check_retirable(
+    task: &Task,
+    channel_id: ChannelId,
+    message_id: MessageId,
+) -> SyscallResult<Range<VirtualAddress>, KError> {
+    let region = validate_message_region(task, channel_id, message_id, MessageDirection::Incoming)?;
+
+    if !task.cspace.grants_channel(task.channels[&channel_id].capability, channel_id, CapabilityRights::READ) {
+        return SyscallResult::Err(KError::InvalidCapability);
+    }
+
+    SyscallResult::Ok(region)
+}
+
+/// Retire a message which [`check_retirable`] has already let through, which
+/// can't fail
+fn retire_checked(task: &mut Task, channel_id: ChannelId, message_id: MessageId, region: Range<VirtualAddress>) {
+    let channel = task.channels.get_mut(&channel_id).unwrap();
+    channel.read_regions.remove(&message_id);
+    channel.messages_retired += 1;
+    let (sender, sender_channel_id) = (channel.other_task, channel.other_channel_id);
+    dealloc_message_region(task, region);
+    return_credit(sender, sender_channel_id);
+}
+
+/// The sender used up a credit on its end of the channel, `sender_channel_id`,
+/// to send a message, so once the message is retired there's room for another
+fn return_credit(sender: Tid, sender_channel_id: ChannelId) {
+    if let Some(other) = TASKS.get(sender) {
+        let mut other = other.lock();
+        let sender_blocked = match other.channels.get_mut(&sender_channel_id) {
+            Some(other_channel) => other_channel.return_credits(1),
+            None => false,
+        };
+
+        if sender_blocked {
+            unblock(&mut other);
+        }
+    }
+}
+
+/// Allow the other end of a flow controlled channel to send `credits` more
+/// messages, in addition to those it gets back as messages are retired
+pub fn grant_channel_credits(task: &mut Task, channel_id: usize, credits: usize) -> SyscallResult<(), KError> {
+    let channel = match task.channels.get(&ChannelId::new(channel_id)) {
+        Some(channel) => channel,
+        None => return SyscallResult::Err(KError::InvalidChannelArgument(0, ChannelArgumentError::UnknownChannel)),
+    };
+
+    let other = match TASKS.get(channel.other_task) {
+        Some(other) => other,
+        None => return SyscallResult::Err(KError::InvalidRecipient),
+    };
+    let mut other = other.lock();
+
+    if !peer_channel_open(&other, channel) {
+        return SyscallResult::Err(KError::InvalidRecipient);
+    }
+
+    let other_channel = other.channels.get_mut(&channel.other_channel_id).unwrap();
+    if other_channel.credits.is_none() {
+        return SyscallResult::Err(KError::InvalidOperation);
+    }
+
+    if other_channel.return_credits(credits) {
+        unblock(&mut other);
+    }
+
+    let notification = KernelNotification::ChannelCreditsGranted { channel: channel.other_channel_id, credits };
+    other.message_queue.push_back((Sender::kernel(), notification.into()));
+
+    SyscallResult::Ok(())
+}
+
+/// Shrink a message created with [`create_message`] which hasn't been sent yet
+/// down to the pages needed to hold `new_size` bytes, freeing the rest so they
+/// aren't handed over to the receiver for nothing. Returns the new capacity of
+/// the message, or [`KError::InvalidOperation`] with the message left as it
+/// was if its memory can't be freed.
+pub fn trim_message(
+    task: &mut Task,
+    channel_id: usize,
+    message_id: usize,
+    new_size: usize,
+) -> SyscallResult<usize, KError> {
+    let channel_id = ChannelId::new(channel_id);
+    let message_id = MessageId::new(message_id);
+    let range = validate_message_region(task, channel_id, message_id, MessageDirection::Outgoing)?;
+    let capacity = range.end.as_usize() - range.start.as_usize();
+
+    if new_size == 0 {
+        return SyscallResult::Err(KError::InvalidChannelArgument(2, ChannelArgumentError::ZeroLength));
+    } else if new_size > capacity {
+        return SyscallResult::Err(KError::InvalidChannelArgument(2, ChannelArgumentError::LengthExceedsRegion));
+    }
+
+    // The message holds its own reference to the backing memory, which would
+    // otherwise stop the trailing pages from being freed
+    let channel = task.channels.get_mut(&channel_id).unwrap();
+    drop(channel.write_regions.remove(&message_id));
+
+    let shrunk = task.memory_manager.shrink_shared_region(range.start, PageSize::Kilopage.pages_for(new_size));
+    let backing = match task.memory_manager.region_for(range.start) {
+        Some(AddressRegion { region: Some(MemoryRegion::Backed(PhysicalRegion::Shared(backing))), .. }) => {
+            backing.clone()
+        }
+        _ => unreachable!("message region was validated above"),
+    };
+
+    let region = shrunk.clone().unwrap_or(range);
+    channel.write_regions.insert(message_id, OutgoingMessage { region, backing });
+
+    match shrunk {
+        Some(region) => {
+            let new_capacity = region.end.as_usize() - region.start.as_usize();
+            task.shared_memory_bytes -= capacity - new_capacity;
+
+            SyscallResult::Ok(new_capacity)
+        }
+        // The arguments were fine, it's the backing memory still being shared
+        // with something else that stops it from being freed
+        None => SyscallResult::Err(KError::InvalidOperation),
+    }
+}
+
+/// Free a message created with [`create_message`] which is no longer going to
+/// be sent
+pub fn cancel_message(task: &mut Task, channel_id: usize, message_id: usize) -> SyscallResult<(), KError> {
+    let id = ChannelId::new(channel_id);
+    let message_id = MessageId::new(message_id);
+    let region = validate_message_region(task, id, message_id, MessageDirection::Outgoing)?;
+    let channel = task.channels.get_mut(&id).unwrap();
+
+    channel.write_regions.remove(&message_id);
+    dealloc_message_region(task, region);
+
+    SyscallResult::Ok(())
+}
+
+/// Combines [`retire_message`] and [`create_message`] for request/reply
+/// servers: the request region is retired and a reply region of `reply_len`
+/// bytes is allocated, which is transferred as usual by a later
+/// [`send_message`] once it's been filled in. If either half fails the request
+/// is left unretired.
+pub fn retire_and_reply(
+    task: &mut Task,
+    channel_id: usize,
+    message_id: usize,
+    reply_len: usize,
+) -> SyscallResult<(usize, usize, usize, usize), KError> {
+    let id = ChannelId::new(channel_id);
+    let request_id = MessageId::new(message_id);
+    let request = check_retirable(task, id, request_id)?;
+
+    // The request is freed along with the reply being created, so it doesn't
+    // count against the quota the reply has to fit in
+    let request_bytes = request.end.as_usize() - request.start.as_usize();
+    task.shared_memory_bytes -= request_bytes;
+    let reply = create_message(task, channel_id, reply_len);
+    task.shared_memory_bytes += request_bytes;
+
+    let reply = reply?;
+    retire_checked(task, id, request_id, request);
+
+    SyscallResult::Ok(reply)
+}
+
+pub fn channel_stats(task: &mut Task, channel_id: usize) -> SyscallResult<ChannelStats, KError> {
+    match task.channels.get(&ChannelId::new(channel_id)) {
+        Some(channel) => SyscallResult::Ok(channel.stats()),
+        None => SyscallResult::Err(KError::InvalidChannelArgument(0, ChannelArgumentError::UnknownChannel)),
+    }
+}
+
+/// The channels which have received messages that haven't been read yet,
+/// bounded to what can be returned in a single syscall
+pub fn poll_ready_channels(task: &Task) -> impl Iterator<Item = ChannelId> + '_ {
+    task.channels
+        .iter()
+        .filter(|(_, channel)| !channel.read_regions.is_empty())
+        .map(move |(id, _)| open_handle(task, *id))
+        .take(MAX_READY_CHANNELS)
+}
+
+/// Attach a human-readable label to the task's end of a channel, which is
+/// included when dumping the channel
+pub fn set_channel_label(task: &mut Task, channel_id: usize, label: &str) -> SyscallResult<(), KError> {
+    let channel = match task.channels.get_mut(&ChannelId::new(channel_id)) {
+        Some(channel) => channel,
+        None => return SyscallResult::Err(KError::InvalidChannelArgument(0, ChannelArgumentError::UnknownChannel)),
+    };
+
+    match channel.set_label(label) {
+        Ok(()) => SyscallResult::Ok(()),
+        Err(e) => SyscallResult::Err(e),
+    }
+}
+
+/// The label of the task's end of a channel, if it has one
+pub fn channel_label(task: &Task, channel_id: usize) -> SyscallResult<Option<&str>, KError> {
+    match task.channels.get(&ChannelId::new(channel_id)) {
+        Some(channel) => SyscallResult::Ok(channel.label()),
+        None => SyscallResult::Err(KError::InvalidChannelArgument(0, ChannelArgumentError::UnknownChannel)),
+    }
+}
+
+/// Log the full state of a channel for debugging IPC issues, only available in
+/// debug builds
+pub fn dump_channel(task: &mut Task, channel_id: usize) -> SyscallResult<(), KError> {
+    if !cfg!(debug_assertions) {
+        return SyscallResult::Err(KError::InvalidSyscall(Syscall::DumpChannel as usize));
+    }
+
+    let id = ChannelId::new(channel_id);
+    let channel = match task.channels.get(&id) {
+        Some(channel) => channel,
+        None => return SyscallResult::Err(KError::InvalidChannelArgument(0, ChannelArgumentError::UnknownChannel)),
+    };
+
+    log::info!(
+        "Channel {:?} ({}) of {:?}: peer={:?} peer_channel={:?} message_id_counter={}",
+        id,
+        channel.label().unwrap_or("unlabeled"),
+        CURRENT_TASK.get().unwrap(),
+        channel.other_task,
+        channel.other_channel_id,
+        channel.message_id_counter.load(Ordering::Acquire),
+    );
+
+    for (message_id, OutgoingMessage { region: range, .. }) in &channel.write_regions {
+        log::info!(
+            "    write {:?}: {:#p}-{:#p} (len={})",
+            message_id,
+            range.start,
+            range.end,
+            range.end.as_usize() - range.start.as_usize()
+        );
+    }
+
+    for (message_id, message) in &channel.read_regions {
+        log::info!(
+            "    read {:?}: {:#p}-{:#p} (len={}, reply_to={:?})",
+            message_id,
+            message.region.start,
+            message.region.end,
+            message.len,
+            message.reply_to
+        );
+    }
+
+    SyscallResult::Ok(())
+}
+
+/// The maximum number of channels reclaimed by a single call to
+/// [`gc_channels`], so that a task with lots of abandoned channels can't stall
+/// the hart for too long
+pub const MAX_CHANNEL_GC_SWEEP: usize = 16;
+
+/// Reclaim channels whose other end has gone away, along with any messages
+/// still on them, notifying the task of each one that's closed. Returns the
+/// number of channels reclaimed.
+pub fn gc_channels(task: &mut Task) -> SyscallResult<usize, KError> {
+    let abandoned: Vec<(ChannelId, Option<ExitReason>)> = task
+        .channels
+        .iter()
+        .filter_map(|(id, channel)| match TASKS.get(channel.other_task) {
+            _ if channel.peer_closed => Some((*id, channel.peer_exit)),
+            Some(other) => {
+                let other = other.lock();
+                match peer_channel_open(&other, channel) {
+                    true => None,
+                    false => Some((*id, other.state.exit_reason())),
+                }
+            }
+            None => Some((*id, None)),
+        })
+        .take(MAX_CHANNEL_GC_SWEEP)
+        .collect();
+
+    for &(id, reason) in &abandoned {
+        let channel = task.channels.remove(&id).unwrap();
+        task.cspace.revoke(channel.capability);
+        free_channel_messages(task, channel.write_regions, channel.read_regions);
+        task.channel_dups.retain(|_, original| *original != id);
+
+        task.message_queue
+            .push_back((Sender::kernel(), KernelNotification::ChannelClosed { channel: id, reason }.into()));
+    }
+
+    SyscallResult::Ok(abandoned.len())
+}
+
+/// Connect the far ends of the task's channels `a` and `b` directly to each
+/// other, so that a task proxying between two peers can drop out of the path
+/// once it's done its part. The task's ends of both channels are closed.
+/// Messages the task received on either channel and hasn't retired are moved
+/// over to the peer on the other side as if they'd been sent there directly.
+pub fn splice_channels(task: &mut Task, a: usize, b: usize) -> SyscallResult<(), KError> {
+    let (a, b) = (ChannelId::new(a), ChannelId::new(b));
+    if a == b {
+        return SyscallResult::Err(KError::InvalidArgument(1));
+    }
+
+    for (argument, id) in [(0, a), (1, b)] {
+        let channel = match task.channels.get(&id) {
+            Some(channel) => channel,
+            None => {
+                return SyscallResult::Err(KError::InvalidChannelArgument(
+                    argument,
+                    ChannelArgumentError::UnknownChannel,
+                ))
+            }
+        };
+
+        let rights = CapabilityRights::READ | CapabilityRights::WRITE;
+        if !task.cspace.grants_channel(channel.capability, id, rights) {
+            return SyscallResult::Err(KError::InvalidCapability);
+        }
+
+        // The lender recalls lent regions from the task it lent them to, so
+        // they can't be moved on to somebody else
+        let holds_borrowed = channel.read_regions.values().any(|message| {
+            matches!(
+                task.memory_manager.region_for(message.region.start),
+                Some(AddressRegion { kind: AddressRegionKind::Borrowed, .. })
+            )
+        });
+
+        if holds_borrowed {
+            return SyscallResult::Err(KError::InvalidOperation);
+        }
+    }
+
+    let (x_tid, xa) = (task.channels[&a].other_task, task.channels[&a].other_channel_id);
+    let (y_tid, yb) = (task.channels[&b].other_task, task.channels[&b].other_channel_id);
+
+    // Splicing two channels to the same task would connect it to itself
+    if x_tid == y_tid {
+        return SyscallResult::Err(KError::InvalidChannelArgument(1, ChannelArgumentError::SameTask));
+    }
+
+    let (x_task, y_task) = match (TASKS.get(x_tid), TASKS.get(y_tid)) {
+        (Some(x_task), Some(y_task)) => (x_task, y_task),
+        _ => return SyscallResult::Err(KError::ChannelClosed),
+    };
+
+    // Always lock the peers in the same order so that two splices involving
+    // the same pair of tasks can't deadlock on each other
+    let (mut x, mut y) = match x_tid < y_tid {
+        true => {
+            let x = x_task.lock();
+            (x, y_task.lock())
+        }
+        false => {
+            let y = y_task.lock();
+            (x_task.lock(), y)
+        }
+    };
+
+    if !peer_channel_open(&x, &task.channels[&a]) || !peer_channel_open(&y, &task.channels[&b]) {
+        return SyscallResult::Err(KError::ChannelClosed);
+    }
+
+    // Check that everything pending fits in the peers before anything is moved
+    let pending_bytes = |channel: &UserspaceChannel| -> usize {
+        channel
+            .read_regions
+            .values()
+            .map(|message| message.region.end.as_usize() - message.region.start.as_usize())
+            .sum()
+    };
+
+    if y.shared_memory_bytes + pending_bytes(&task.channels[&a]) > y.shared_memory_quota
+        || x.shared_memory_bytes + pending_bytes(&task.channels[&b]) > x.shared_memory_quota
+    {
+        return SyscallResult::Err(KError::RecipientFull);
+    }
+
+    // Likewise for address space, mapping everything into the peers up front
+    // so that there's nothing to undo once the channels start being torn down
+    let mapped_in_y = match map_pending_messages(task, &task.channels[&a].read_regions, &mut y) {
+        Some(mapped) => mapped,
+        None => return SyscallResult::Err(KError::RecipientFull),
+    };
+    let mapped_in_x = match map_pending_messages(task, &task.channels[&b].read_regions, &mut x) {
+        Some(mapped) => mapped,
+        None => {
+            unmap_pending_messages(&mut y, mapped_in_y);
+            return SyscallResult::Err(KError::RecipientFull);
+        }
+    };
+
+    // Message IDs from both sides end up in the same read queues, so the peers
+    // share a counter that continues on from both of their old ones
+    let next_id = Ord::max(
+        x.channels[&xa].message_id_counter.load(Ordering::Acquire),
+        y.channels[&yb].message_id_counter.load(Ordering::Acquire),
+    );
+    let counter = Arc::new(AtomicUsize::new(next_id));
+
+    let channel_a = task.channels.remove(&a).unwrap();
+    let channel_b = task.channels.remove(&b).unwrap();
+    task.cspace.revoke(channel_a.capability);
+    task.cspace.revoke(channel_b.capability);
+    let (a_credits, b_credits) = (channel_a.credits, channel_b.credits);
+    task.channel_dups.retain(|_, original| *original != a && *original != b);
+
+    let moved_to_y = move_pending_messages(task, channel_a.read_regions, mapped_in_y, &mut y, yb, &counter);
+    let moved_to_x = move_pending_messages(task, channel_b.read_regions, mapped_in_x, &mut x, xa, &counter);
+    free_channel_messages(task, channel_a.write_regions, BTreeMap::new());
+    free_channel_messages(task, channel_b.write_regions, BTreeMap::new());
+
+    let x_blocked = x.channels.get_mut(&xa).unwrap().rebind(y_tid, yb, b_credits, moved_to_y, counter.clone());
+    let y_blocked = y.channels.get_mut(&yb).unwrap().rebind(x_tid, xa, a_credits, moved_to_x, counter);
+
+    for (peer, blocked, moved) in [(&mut *x, x_blocked, moved_to_x), (&mut *y, y_blocked, moved_to_y)] {
+        if blocked {
+            unblock(peer);
+        }
+
+        if moved > 0 {
+            wake_receiver(peer);
+        }
+    }
+
+    SyscallResult::Ok(())
+}
+
+/// Map the messages `task` has received in `messages` into `to` as well, so
+/// that nothing can fail once they're moved over. Returns where each message
+/// was mapped in `to`, in the same order as `messages`, or `None` if `to` ran
+/// out of address space, in which case nothing is left mapped in `to`.
+fn map_pending_messages(
+    task: &Task,
+    messages: &BTreeMap<MessageId, ReceivedMessage>,
+    to: &mut Task,
+) -> Option<Vec<Range<VirtualAddress>>> {
+    let mut mapped = Vec::with_capacity(messages.len());
+
+    for message in messages.values() {
+        let backing = match task.memory_manager.region_for(message.region.start) {
+            Some(AddressRegion { region: Some(MemoryRegion::Backed(PhysicalRegion::Shared(backing))), .. }) => {
+                backing.clone()
+            }
+            _ => unreachable!("received messages are always shared memory"),
+        };
+
+        let flags = flags::READ | flags::WRITE | flags::USER | flags::VALID;
+        match to.memory_manager.apply_shared_region(None, flags, backing, AddressRegionKind::Channel) {
+            Ok(region) => mapped.push(region),
+            Err(_) => {
+                unmap_pending_messages(to, mapped);
+                return None;
+            }
+        }
+    }
+
+    Some(mapped)
+}
+
+/// Undo [`map_pending_messages`]
+fn unmap_pending_messages(to: &mut Task, mapped: Vec<Range<VirtualAddress>>) {
+    for region in mapped {
+        to.memory_manager.dealloc_region(region.start);
+    }
+}
+
+/// Move messages received by `task` over to `to`'s end of a channel,
+/// `to_channel_id`, where they've already been mapped at `mapped` by
+/// [`map_pending_messages`]. Messages keep their IDs unless `to` already has
+/// a message with the same ID pending, in which case they're given a new one
+/// from `counter`. Returns the number of messages moved.
+fn move_pending_messages(
+    task: &mut Task,
+    messages: BTreeMap<MessageId, ReceivedMessage>,
+    mapped: Vec<Range<VirtualAddress>>,
+    to: &mut Task,
+    to_channel_id: ChannelId,
+    counter: &AtomicUsize,
+) -> usize {
+    let moved = messages.len();
+
+    for ((mut message_id, message), region) in messages.into_iter().zip(mapped) {
+        let size = message.region.end.as_usize() - message.region.start.as_usize();
+        task.memory_manager.dealloc_region(message.region.start);
+
+        task.shared_memory_bytes -= size;
+        to.shared_memory_bytes += size;
+
+        let to_channel = to.channels.get_mut(&to_channel_id).unwrap();
+        if to_channel.read_regions.contains_key(&message_id) {
+            message_id = MessageId::new(counter.fetch_add(1, Ordering::AcqRel));
+        }
+
+        to_channel.receive(message_id, ReceivedMessage { region, ..message });
+    }
+
+    moved
+}
+
+/// Close every channel belonging to `dying`, which has just died, freeing the
+/// messages still on them. The other end of each channel is marked closed, see
+/// [`close_peer_end`], and its messages are freed when its task reclaims it
+/// with [`gc_channels`] once it's done with it.
+/// `current` is the task performing the syscall when that isn't `dying`
+/// itself, which is already locked by the caller and so needs special cased.
+pub fn close_all_channels(dying: &mut Task, mut current: Option<(Tid, &mut Task)>) {
+    let reason = dying.state.exit_reason();
+    dying.channel_dups.clear();
+
+    for (_, channel) in core::mem::take(&mut dying.channels) {
+        dying.cspace.revoke(channel.capability);
+        free_channel_messages(dying, channel.write_regions, channel.read_regions);
+
+        match &mut current {
+            Some((current_tid, current)) if *current_tid == channel.other_task => {
+                close_peer_end(current, channel.other_channel_id, reason)
+            }
+            _ => {
+                if let Some(other) = TASKS.get(channel.other_task) {
+                    close_peer_end(&mut other.lock(), channel.other_channel_id, reason);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::num::NonZeroUsize;
+    use sync::SpinMutex;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    #[repr(C)]
+    struct Header {
+        kind: u32,
+        len: u32,
+    }
+
+    unsafe impl MessageHeader for Header {}
+
+    fn tid(n: usize) -> Tid {
+        Tid::new(NonZeroUsize::new(n).unwrap())
+    }
+
+    /// One end of a channel to TID 1, with nothing actually on the other end,
+    /// for testing the bookkeeping of the end itself
+    fn lone_channel() -> UserspaceChannel {
+        let counter = Arc::new(AtomicUsize::new(1));
+        UserspaceChannel::new(tid(1), ChannelId::new(1), CapabilityPtr::new(0), MAX_CHANNEL_BYTES, None, counter)
+    }
+
+    /// Two tasks, with TIDs 1 and 2, joined by a channel with the default
+    /// message size limit
+    fn connected_pair(credits: usize) -> (Task, Task, ChannelId, ChannelId) {
+        let mut a = Task::empty("a");
+        let mut b = Task::empty("b");
+        let (a_channel, b_channel) = connect_channel(&mut a, tid(1), &mut b, tid(2), 0, credits);
+
+        (a, b, a_channel, b_channel)
+    }
+
+    /// A task registered in [`TASKS`] the way the scheduler would, so that
+    /// syscalls on other tasks can find it, which is removed again once this
+    /// is dropped
+    struct Registered {
+        tid: Tid,
+        task: Arc<SpinMutex<Task>>,
+    }
+
+    impl Drop for Registered {
+        fn drop(&mut self) {
+            TASKS.remove(self.tid);
+        }
+    }
+
+    fn register(name: &str) -> Registered {
+        let (tid, task) = TASKS.insert(Task::empty(name));
+        Registered { tid, task }
+    }
+
+    /// Same as [`connected_pair`], but with both tasks registered
+    fn registered_pair(credits: usize) -> (Registered, Registered, ChannelId, ChannelId) {
+        let a = register("a");
+        let b = register("b");
+        let (a_channel, b_channel) = connect_channel(&mut a.task.lock(), a.tid, &mut b.task.lock(), b.tid, 0, credits);
+
+        (a, b, a_channel, b_channel)
+    }
+
+    #[test]
+    fn channel_requests_in_arrival_order() {
+        let mut requests = ChannelRequests::default();
+
+        requests.insert(tid(5));
+        requests.insert(tid(2));
+        requests.insert(tid(9));
+        // Requesting again doesn't move a task to the back of the line
+        requests.insert(tid(5));
+
+        assert_eq!(requests.iter().collect::<Vec<_>>(), [tid(5), tid(2), tid(9)]);
+        assert_eq!(requests.oldest(), Some(tid(5)));
+
+        assert!(requests.remove(&tid(5)));
+        assert!(!requests.remove(&tid(5)));
+        assert_eq!(requests.oldest(), Some(tid(2)));
+    }
+
+    #[test]
+    fn read_header_from_message() {
+        let (mut sender, mut receiver, sender_channel, receiver_channel) = connected_pair(0);
+        let mut message = [0; 8];
+        message[..4].copy_from_slice(&7u32.to_ne_bytes());
+        message[4..].copy_from_slice(&12u32.to_ne_bytes());
+        seed_message(sender.channels.get_mut(&sender_channel).unwrap(), &mut receiver, receiver_channel, &message);
+
+        let message_id = *receiver.channels[&receiver_channel].read_regions.keys().next().unwrap();
+        let header = read_message_header::<Header>(&receiver, receiver_channel, message_id);
+        assert_eq!(header, Some(Header { kind: 7, len: 12 }));
+    }
+
+    #[test]
+    fn reject_undersized_or_unknown_header() {
+        let (mut sender, mut receiver, sender_channel, receiver_channel) = connected_pair(0);
+        seed_message(sender.channels.get_mut(&sender_channel).unwrap(), &mut receiver, receiver_channel, &[1; 7]);
+
+        let message_id = *receiver.channels[&receiver_channel].read_regions.keys().next().unwrap();
+        assert_eq!(read_message_header::<Header>(&receiver, receiver_channel, message_id), None);
+
+        let unknown = MessageId::new(message_id.value() + 1);
+        assert_eq!(read_message_header::<Header>(&receiver, receiver_channel, unknown), None);
+    }
+
+    #[test]
+    fn given_region_never_gains_write() {
+        let read_only = flags::READ | flags::USER | flags::VALID;
+        let read_write = flags::READ | flags::WRITE | flags::USER | flags::VALID;
+
+        assert_eq!(peer_region_flags(read_only), read_only);
+        assert_eq!(peer_region_flags(read_write), read_write);
+        assert_eq!(peer_region_flags(read_write | flags::EXECUTE | flags::DIRTY), read_write);
+    }
+
+    #[test]
+    fn set_and_read_back_label() {
+        let mut channel = lone_channel();
+
+        assert_eq!(channel.label(), None);
+        channel.set_label("block device").unwrap();
+        assert_eq!(channel.label(), Some("block device"));
+
+        // Too long labels are rejected without touching the current one
+        let too_long = "a".repeat(MAX_CHANNEL_LABEL_LEN + 1);
+        assert!(channel.set_label(&too_long).is_err());
+        assert_eq!(channel.label(), Some("block device"));
+
+        channel.set_label("").unwrap();
+        assert_eq!(channel.label(), None);
+    }
+
+    #[test]
+    fn channel_open_until_last_handle_dropped() {
+        let (a, b, a_channel, b_channel) = registered_pair(0);
+        let peer_open = || matches!(read_message(&mut b.task.lock(), b_channel.value()), SyscallResult::Ok(_));
+
+        let dup = dup_channel(&mut a.task.lock(), a_channel.value()).unwrap();
+        assert_ne!(dup, a_channel.value());
+
+        close_channel(&mut a.task.lock(), a_channel.value()).unwrap();
+        assert!(peer_open());
+        // The original handle is gone even though the channel isn't
+        assert!(matches!(close_channel(&mut a.task.lock(), a_channel.value()), SyscallResult::Err(_)));
+
+        close_channel(&mut a.task.lock(), dup).unwrap();
+        assert!(!peer_open());
+        assert!(a.task.lock().channels.is_empty());
+    }
+
+    #[test]
+    fn returned_credit_wakes_blocked_sender() {
+        let (sender, receiver, sender_channel, receiver_channel) = registered_pair(2);
+
+        let mut sender_task = sender.task.lock();
+        let mut message_ids = Vec::new();
+        for _ in 0..3 {
+            let (message_id, ..) = create_message(&mut sender_task, sender_channel.value(), 8).unwrap();
+            message_ids.push(message_id);
+        }
+
+        for &message_id in &message_ids[..2] {
+            send_message(&mut sender_task, sender_channel.value(), message_id, 8, 0, 0).unwrap();
+        }
+
+        // Out of credits, so the sender blocks until the receiver retires one
+        let third = message_ids[2];
+        let sent = send_message_blocking(&mut sender_task, sender_channel.value(), third, 8, 0, 0);
+        assert!(matches!(sent, SyscallResult::Err(KError::ChannelFull)));
+        assert!(matches!(sender_task.state, TaskState::Blocked));
+        drop(sender_task);
+
+        let mut receiver_task = receiver.task.lock();
+        let (message_id, ..) = read_message(&mut receiver_task, receiver_channel.value()).unwrap();
+        retire_message(&mut receiver_task, receiver_channel.value(), message_id).unwrap();
+        drop(receiver_task);
+
+        let mut sender_task = sender.task.lock();
+        assert!(matches!(sender_task.state, TaskState::Running));
+        send_message_blocking(&mut sender_task, sender_channel.value(), third, 8, 0, 0).unwrap();
+        assert_eq!(sender_task.channels[&sender_channel].credits, Some(0));
+    }
+
+    #[test]
+    fn spliced_ends_talk_directly() {
+        let x = register("x");
+        let broker = register("broker");
+        let y = register("y");
+        let (xa, a) = connect_channel(&mut x.task.lock(), x.tid, &mut broker.task.lock(), broker.tid, 0, 0);
+        let (yb, b) = connect_channel(&mut y.task.lock(), y.tid, &mut broker.task.lock(), broker.tid, 0, 0);
+
+        // `x` sends a message which the broker never gets around to reading
+        {
+            let mut x_task = x.task.lock();
+            let (message_id, ptr, ..) = create_message(&mut x_task, xa.value(), 4).unwrap();
+            let phys = x_task.memory_manager.resolve(VirtualAddress::new(ptr)).unwrap();
+            unsafe { mem::phys2virt(phys).as_mut_ptr().write(42) };
+            send_message(&mut x_task, xa.value(), message_id, 4, 0, 0).unwrap();
+        }
+
+        splice_channels(&mut broker.task.lock(), a.value(), b.value()).unwrap();
+
+        let broker_task = broker.task.lock();
+        assert!(broker_task.channels.is_empty());
+        assert_eq!(broker_task.shared_memory_bytes, 0);
+        drop(broker_task);
+
+        // The pending message was handed on to `y` as-is
+        let mut y_task = y.task.lock();
+        assert_eq!(y_task.channels[&yb].other_task, x.tid);
+        let (message_id, ptr, len, .., sender) = read_message(&mut y_task, yb.value()).unwrap();
+        assert_eq!((len, sender), (4, x.tid.value()));
+        let phys = y_task.memory_manager.resolve(VirtualAddress::new(ptr)).unwrap();
+        assert_eq!(unsafe { *mem::phys2virt(phys).as_ptr() }, 42);
+        retire_message(&mut y_task, yb.value(), message_id).unwrap();
+        drop(y_task);
+
+        // And from now on `x` sends straight to `y`
+        let mut x_task = x.task.lock();
+        assert_eq!(x_task.channels[&xa].other_task, y.tid);
+        let (message_id, ..) = create_message(&mut x_task, xa.value(), 4).unwrap();
+        send_message(&mut x_task, xa.value(), message_id, 4, 0, 0).unwrap();
+        drop(x_task);
+
+        assert_eq!(y.task.lock().channels[&yb].read_regions.len(), 1);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn freed_message_is_poisoned() {
+        let mut manager = mem::manager::MemoryManager::new();
+        let (range, backing) = manager.alloc_shared_region(
+            None,
+            RegionDescription {
+                size: PageSize::Kilopage,
+                len: 1,
+                contiguous: false,
+                flags: flags::READ | flags::WRITE | flags::USER | flags::VALID,
+                fill: FillOption::Zeroed,
+                kind: AddressRegionKind::Channel,
+            },
+        );
+        let phys = backing.physical_addresses().next().unwrap();
+        drop(backing);
+
+        poison_freed_message(manager.dealloc_region(range.start));
+
+        // Nothing has had the chance to reuse the frame yet
+        let frame = unsafe { core::slice::from_raw_parts(mem::phys2virt(phys).as_ptr(), 4096) };
+        assert!(frame.iter().all(|&byte| byte == 0xAA));
+    }
+
+    #[test]
+    fn stats_follow_send_and_retire() {
+        let (sender, receiver, sender_channel, receiver_channel) = registered_pair(4);
+
+        let send = |len| {
+            let mut sender = sender.task.lock();
+            let (message_id, ..) = create_message(&mut sender, sender_channel.value(), len).unwrap();
+            send_message(&mut sender, sender_channel.value(), message_id, len, 0, 0).unwrap();
+        };
+
+        send(16);
+        send(32);
+        send(64);
+
+        for _ in 0..2 {
+            let mut receiver = receiver.task.lock();
+            let (message_id, ..) = read_message(&mut receiver, receiver_channel.value()).unwrap();
+            retire_message(&mut receiver, receiver_channel.value(), message_id).unwrap();
+        }
+
+        send(8);
+
+        let sent = channel_stats(&mut sender.task.lock(), sender_channel.value()).unwrap();
+        assert_eq!((sent.created, sent.sent, sent.bytes_sent, sent.pending_writes), (4, 4, 120, 0));
+        // Both retired messages gave their credit back
+        assert_eq!(sender.task.lock().channels[&sender_channel].credits, Some(2));
+
+        let received = channel_stats(&mut receiver.task.lock(), receiver_channel.value()).unwrap();
+        assert_eq!((received.read, received.retired), (2, 2));
+        assert_eq!(received.pending_reads, 2);
+        assert_eq!(received.peak_pending_reads, 3);
+    }
+
+    #[test]
+    fn read_message_reports_sender() {
+        let sender = tid(7);
+        let region = VirtualAddress::new(0x1000)..VirtualAddress::new(0x2000);
+        let message = ReceivedMessage { region, len: 8, reply_to: None, sequence: 1, tag: 0, sender };
+
+        let (id, ptr, len, capacity, _, _, _, _, from) = message.to_parts(MessageId::new(3), MessageAccess::ReadOnly);
+        assert_eq!((id, ptr, len, capacity), (3, 0x1000, 8, 0x1000));
+        assert_eq!(from, sender.value());
+    }
+
+    #[test]
+    fn first_ready_channel_finds_message_on_any_channel() {
+        let mut channels: BTreeMap<ChannelId, UserspaceChannel> =
+            (0..3).map(|i| (ChannelId::new(i), lone_channel())).collect();
+
+        assert_eq!(first_ready_channel(&channels), None);
+
+        let region = VirtualAddress::new(0x1000)..VirtualAddress::new(0x2000);
+        let message = ReceivedMessage { region, len: 8, reply_to: None, sequence: 1, tag: 0, sender: tid(1) };
+        channels.get_mut(&ChannelId::new(2)).unwrap().read_regions.insert(MessageId::new(1), message);
+
+        assert_eq!(first_ready_channel(&channels), Some(ChannelId::new(2)));
+    }
+
+    #[test]
+    fn receiver_wakes_when_peer_dies() {
+        let dying_tid = tid(1);
+        let waiter_tid = tid(2);
+        let mut dying = Task::empty("dying");
+        let mut waiter = Task::empty("waiter");
+        let (_, waiter_channel) = connect_channel(&mut dying, dying_tid, &mut waiter, waiter_tid, 0, 0);
+
+        // Nothing has been sent yet, so the waiter blocks
+        assert!(matches!(receive_any(&mut waiter), SyscallResult::Err(KError::NoMessages)));
+        assert!(matches!(waiter.state, TaskState::Blocked));
+
+        crate::syscall::task::mark_dead(&mut dying, ExitReason::Panicked, Some((waiter_tid, &mut waiter)));
+
+        assert!(matches!(waiter.state, TaskState::Running));
+        // Woken with the closed channel rather than a message
+        let closed = receive_any(&mut waiter).unwrap();
+        assert_eq!((closed.0, closed.1), (waiter_channel.value(), 0));
+        assert!(matches!(read_message(&mut waiter, waiter_channel.value()), SyscallResult::Err(KError::ChannelClosed)));
+    }
+
+    #[test]
+    fn killed_task_gives_back_shared_memory() {
+        let parent_tid = tid(1);
+        let child_tid = tid(2);
+        let mut parent = Task::empty("parent");
+        let mut child = Task::empty("child");
+        let before = parent.shared_memory_bytes;
+
+        let (parent_channel, child_channel) = connect_channel(&mut parent, parent_tid, &mut child, child_tid, 0, 0);
+        seed_message(child.channels.get_mut(&child_channel).unwrap(), &mut parent, parent_channel, b"ping");
+        seed_message(parent.channels.get_mut(&parent_channel).unwrap(), &mut child, child_channel, b"pong");
+        assert!(parent.shared_memory_bytes > before);
+
+        crate::syscall::task::mark_dead(&mut child, ExitReason::Killed, Some((parent_tid, &mut parent)));
+        assert_eq!(child.shared_memory_bytes, 0);
+        assert!(child.channels.is_empty());
+
+        // The message sent before the kill can still be read, and the memory
+        // backing it is given back once the parent reclaims the channel
+        assert_ne!(read_message(&mut parent, parent_channel.value()).unwrap().0, 0);
+        assert_eq!(gc_channels(&mut parent).unwrap(), 1);
+        assert_eq!(parent.shared_memory_bytes, before);
+
+        // The child is gone by now, but the parent still finds out why
+        let (_, notification) = parent.message_queue.pop_back().unwrap();
+        assert!(matches!(
+            KernelNotification::from(notification),
+            KernelNotification::ChannelClosed { reason: Some(ExitReason::Killed), .. }
+        ));
+    }
+
+    #[test]
+    fn reclaimed_channels_lose_their_capabilities() {
+        let parent_tid = tid(1);
+        let child_tid = tid(2);
+        let mut parent = Task::empty("parent");
+        let mut child = Task::empty("child");
+
+        let (parent_channel, child_channel) = connect_channel(&mut parent, parent_tid, &mut child, child_tid, 0, 0);
+        let parent_capability = parent.channels[&parent_channel].capability;
+        let child_capability = child.channels[&child_channel].capability;
+
+        crate::syscall::task::mark_dead(&mut child, ExitReason::Exited, Some((parent_tid, &mut parent)));
+        assert!(child.cspace.resolve(child_capability).is_none());
+        assert!(parent.cspace.resolve(parent_capability).is_some());
+
+        assert_eq!(gc_channels(&mut parent).unwrap(), 1);
+        assert!(parent.cspace.resolve(parent_capability).is_none());
+    }
+
+    #[test]
+    fn recall_without_address_space_keeps_region_lent() {
+        let (lender, borrower, lender_channel, borrower_channel) = registered_pair(0);
+        let mut lender = lender.task.lock();
+
+        let range = lender.memory_manager.alloc_region(
+            None,
+            RegionDescription {
+                size: PageSize::Kilopage,
+                len: 1,
+                contiguous: false,
+                flags: flags::READ | flags::WRITE | flags::USER | flags::VALID,
+                fill: FillOption::Zeroed,
+                kind: AddressRegionKind::UserAllocated,
+            },
+        );
+        let message_id = lend_region(&mut lender, lender_channel.value(), range.start, 4096).unwrap();
+
+        // Reserve everything that's left without backing any of it, leaving
+        // nowhere to map the region back into
+        let free: Vec<(VirtualAddress, usize)> = lender
+            .memory_manager
+            .address_map_debug()
+            .unoccupied_regions()
+            .map(|r| (r.span.start, (r.span.end.as_usize() - r.span.start.as_usize()) / 4096))
+            .filter(|&(_, n_pages)| n_pages > 0)
+            .collect();
+        for &(start, n_pages) in &free {
+            let kind = AddressRegionKind::UserAllocated;
+            lender.memory_manager.alloc_lazy_region(Some(start), PageSize::Kilopage, n_pages, flags::VALID, kind);
+        }
+
+        assert!(matches!(
+            recall_region(&mut lender, lender_channel.value(), message_id),
+            SyscallResult::Err(KError::AddressSpaceExhausted)
+        ));
+        assert_eq!(lender.lent_regions.len(), 1);
+        assert_eq!(borrower.task.lock().channels[&borrower_channel].read_regions.len(), 1);
+
+        // Once there's room again the recall can be retried
+        lender.memory_manager.dealloc_region(free[0].0);
+        assert!(matches!(recall_region(&mut lender, lender_channel.value(), message_id), SyscallResult::Ok(_)));
+        assert!(lender.lent_regions.is_empty());
+        assert!(borrower.task.lock().channels[&borrower_channel].read_regions.is_empty());
+    }
+
+    #[test]
+    fn failed_reply_leaves_request_unretired() {
+        let (mut sender, mut receiver, sender_channel, receiver_channel) = connected_pair(0);
+        seed_message(sender.channels.get_mut(&sender_channel).unwrap(), &mut receiver, receiver_channel, &[1; 8]);
+        let request = *receiver.channels[&receiver_channel].read_regions.keys().next().unwrap();
+
+        // The reply can take over the request's page once it's retired, but
+        // can't grow any larger than that
+        receiver.shared_memory_quota = receiver.shared_memory_bytes;
+        let res = retire_and_reply(&mut receiver, receiver_channel.value(), request.value(), 4097);
+        assert!(matches!(res, SyscallResult::Err(KError::QuotaExceeded)));
+        assert!(receiver.channels[&receiver_channel].read_regions.contains_key(&request));
+
+        let (reply, ..) = retire_and_reply(&mut receiver, receiver_channel.value(), request.value(), 4096).unwrap();
+        assert!(receiver.channels[&receiver_channel].read_regions.is_empty());
+        assert!(receiver.channels[&receiver_channel].write_regions.contains_key(&MessageId::new(reply)));
+        assert_eq!(receiver.shared_memory_bytes, 4096);
+    }
+
+    #[test]
+    fn trim_rejects_empty_or_shared_messages() {
+        let (mut task, _other, channel, _) = connected_pair(0);
+        let (id, _, _, capacity) = create_message(&mut task, channel.value(), 3 * 4096).unwrap();
+
+        assert!(matches!(
+            trim_message(&mut task, channel.value(), id, 0),
+            SyscallResult::Err(KError::InvalidChannelArgument(2, ChannelArgumentError::ZeroLength))
+        ));
+
+        // Something else holding on to the memory stops it from being freed,
+        // which leaves the message as it was
+        let backing = task.channels[&channel].write_regions[&MessageId::new(id)].backing.clone();
+        assert!(matches!(
+            trim_message(&mut task, channel.value(), id, 1),
+            SyscallResult::Err(KError::InvalidOperation)
+        ));
+        let region = validate_message_region(&task, channel, MessageId::new(id), MessageDirection::Outgoing).unwrap();
+        assert_eq!(region.end.as_usize() - region.start.as_usize(), capacity);
+        assert_eq!(task.shared_memory_bytes, capacity);
+
+        drop(backing);
+        assert_eq!(trim_message(&mut task, channel.value(), id, 1).unwrap(), 4096);
+        assert_eq!(task.shared_memory_bytes, 4096);
     }
 }
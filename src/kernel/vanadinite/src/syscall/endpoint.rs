@@ -0,0 +1,239 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2021 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{
+    capabilities::{Capability, CapabilityResource, CapabilityRights},
+    scheduler::{CURRENT_TASK, TASKS},
+    task::Task,
+};
+use alloc::collections::VecDeque;
+use librust::{
+    capabilities::CapabilityPtr,
+    error::KError,
+    message::{Message, SyscallResult},
+    syscalls::endpoint::{EndpointId, EndpointMessage, ENDPOINT_MESSAGE_WORDS, MAX_ENDPOINT_MESSAGES},
+    task::Tid,
+};
+
+/// A single receive point that any task can send messages to, for servers
+/// which would otherwise need a channel per client
+pub struct Endpoint {
+    capability: CapabilityPtr,
+    queue: VecDeque<EndpointMessage>,
+}
+
+pub fn create_endpoint(task: &mut Task) -> SyscallResult<usize, KError> {
+    SyscallResult::Ok(open_endpoint(task, CURRENT_TASK.get().unwrap()).value())
+}
+
+/// Create a new endpoint on `task`, whose [`Tid`] is `tid`, along with the
+/// capability to receive from, send to, and grant it
+fn open_endpoint(task: &mut Task, tid: Tid) -> EndpointId {
+    let id = EndpointId::new(task.endpoint_next_id);
+    task.endpoint_next_id += 1;
+
+    let capability = task.cspace.mint(Capability {
+        resource: CapabilityResource::Endpoint(tid, id),
+        rights: CapabilityRights::READ | CapabilityRights::WRITE | CapabilityRights::GRANT,
+    });
+
+    task.endpoints.insert(id, Endpoint { capability, queue: VecDeque::new() });
+
+    id
+}
+
+/// Give `to` a capability to send to the task's endpoint `endpoint_id`
+pub fn grant_endpoint(task: &mut Task, endpoint_id: usize, to: Tid) -> SyscallResult<(), KError> {
+    let current_tid = CURRENT_TASK.get().unwrap();
+    let id = EndpointId::new(endpoint_id);
+
+    if !task.endpoints.contains_key(&id) {
+        return SyscallResult::Err(KError::InvalidArgument(0));
+    }
+
+    if !task.cspace.grants_endpoint(current_tid, id, CapabilityRights::GRANT) {
+        return SyscallResult::Err(KError::InvalidCapability);
+    }
+
+    // The owner can always send to its own endpoints
+    if to == current_tid {
+        return SyscallResult::Ok(());
+    }
+
+    let to_task = match TASKS.get(to) {
+        Some(task) => task,
+        None => return SyscallResult::Err(KError::InvalidRecipient),
+    };
+
+    let mut to_task = to_task.lock();
+
+    if to_task.state.is_dead() {
+        return SyscallResult::Err(KError::InvalidRecipient);
+    }
+
+    give_endpoint(&mut to_task, current_tid, id);
+
+    SyscallResult::Ok(())
+}
+
+/// Mint `to` a capability to send to `owner`'s endpoint `id`, unless it
+/// already has one
+fn give_endpoint(to: &mut Task, owner: Tid, id: EndpointId) {
+    if !to.cspace.grants_endpoint(owner, id, CapabilityRights::WRITE) {
+        let resource = CapabilityResource::Endpoint(owner, id);
+        to.cspace.mint(Capability { resource, rights: CapabilityRights::WRITE });
+    }
+}
+
+/// Send a message to `to`'s endpoint `endpoint_id`, which the task needs to
+/// have been given a capability for with [`grant_endpoint`]
+pub fn send_endpoint(
+    task: &mut Task,
+    to: Tid,
+    endpoint_id: usize,
+    contents: [usize; ENDPOINT_MESSAGE_WORDS],
+) -> SyscallResult<(), KError> {
+    let current_tid = CURRENT_TASK.get().unwrap();
+    let message = endpoint_message(task, current_tid, to, endpoint_id, contents)?;
+
+    // The current task is already locked, so sending to one of its own
+    // endpoints needs to go through the existing reference
+    if to == current_tid {
+        return push_message(task, endpoint_id, message);
+    }
+
+    let to_task = match TASKS.get(to) {
+        Some(task) => task,
+        None => return SyscallResult::Err(KError::InvalidRecipient),
+    };
+
+    let mut to_task = to_task.lock();
+
+    if to_task.state.is_dead() {
+        return SyscallResult::Err(KError::InvalidRecipient);
+    }
+
+    push_message(&mut to_task, endpoint_id, message)
+}
+
+/// Build the message `task`, whose [`Tid`] is `tid`, is sending to `to`'s
+/// endpoint `endpoint_id`, provided it holds a capability to send there
+fn endpoint_message(
+    task: &Task,
+    tid: Tid,
+    to: Tid,
+    endpoint_id: usize,
+    contents: [usize; ENDPOINT_MESSAGE_WORDS],
+) -> SyscallResult<EndpointMessage, KError> {
+    match task.cspace.grants_endpoint(to, EndpointId::new(endpoint_id), CapabilityRights::WRITE) {
+        true => SyscallResult::Ok(EndpointMessage { sender: tid, contents }),
+        false => SyscallResult::Err(KError::InvalidCapability),
+    }
+}
+
+fn push_message(task: &mut Task, endpoint_id: usize, message: EndpointMessage) -> SyscallResult<(), KError> {
+    match task.endpoints.get_mut(&EndpointId::new(endpoint_id)) {
+        // Senders don't need the owner's permission for each message, so make
+        // sure they can't make it queue up without limit
+        Some(endpoint) if endpoint.queue.len() >= MAX_ENDPOINT_MESSAGES => SyscallResult::Err(KError::RecipientFull),
+        Some(endpoint) => {
+            endpoint.queue.push_back(message);
+            SyscallResult::Ok(())
+        }
+        None => SyscallResult::Err(KError::InvalidArgument(1)),
+    }
+}
+
+/// Take the oldest message from one of the task's endpoints
+pub fn receive_endpoint(task: &mut Task, endpoint_id: usize) -> SyscallResult<Message, KError> {
+    let id = EndpointId::new(endpoint_id);
+    let endpoint = match task.endpoints.get_mut(&id) {
+        Some(endpoint) => endpoint,
+        None => return SyscallResult::Err(KError::InvalidArgument(0)),
+    };
+
+    match task.cspace.resolve(endpoint.capability) {
+        Some(Capability { resource: CapabilityResource::Endpoint(_, cap_id), rights })
+            if *cap_id == id && *rights & CapabilityRights::READ => {}
+        _ => return SyscallResult::Err(KError::InvalidCapability),
+    }
+
+    match endpoint.queue.pop_front() {
+        Some(message) => SyscallResult::Ok(message.into()),
+        None => SyscallResult::Err(KError::NoMessages),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::num::NonZeroUsize;
+
+    fn tid(n: usize) -> Tid {
+        Tid::new(NonZeroUsize::new(n).unwrap())
+    }
+
+    /// Send a message filled with `word` from `from` to the endpoint `id` of
+    /// `server`, which is always TID 1, the way [`send_endpoint`] does
+    fn send(from: &Task, from_tid: Tid, server: &mut Task, id: EndpointId, word: usize) -> SyscallResult<(), KError> {
+        let message = endpoint_message(from, from_tid, tid(1), id.value(), [word; ENDPOINT_MESSAGE_WORDS])?;
+        push_message(server, id.value(), message)
+    }
+
+    #[test]
+    fn receiver_sees_each_sender() {
+        let mut server = Task::empty("server");
+        let mut a = Task::empty("a");
+        let mut b = Task::empty("b");
+        let id = open_endpoint(&mut server, tid(1));
+        give_endpoint(&mut a, tid(1), id);
+        give_endpoint(&mut b, tid(1), id);
+
+        send(&a, tid(2), &mut server, id, 2).unwrap();
+        send(&b, tid(3), &mut server, id, 3).unwrap();
+        send(&a, tid(2), &mut server, id, 4).unwrap();
+
+        for (sender, word) in [(2, 2), (3, 3), (2, 4)] {
+            let message = receive_endpoint(&mut server, id.value()).unwrap();
+            assert_eq!(message.contents[0], sender);
+            assert_eq!(message.contents[1..][..ENDPOINT_MESSAGE_WORDS], [word; ENDPOINT_MESSAGE_WORDS]);
+        }
+
+        assert!(matches!(receive_endpoint(&mut server, id.value()), SyscallResult::Err(KError::NoMessages)));
+    }
+
+    #[test]
+    fn full_endpoint_rejects_messages() {
+        let mut server = Task::empty("server");
+        let mut client = Task::empty("client");
+        let id = open_endpoint(&mut server, tid(1));
+        give_endpoint(&mut client, tid(1), id);
+
+        for i in 0..MAX_ENDPOINT_MESSAGES {
+            send(&client, tid(2), &mut server, id, i).unwrap();
+        }
+
+        assert!(matches!(send(&client, tid(2), &mut server, id, 0), SyscallResult::Err(KError::RecipientFull)));
+
+        // Receiving one makes room for another
+        receive_endpoint(&mut server, id.value()).unwrap();
+        send(&client, tid(2), &mut server, id, 0).unwrap();
+    }
+
+    #[test]
+    fn ungranted_sender_is_rejected() {
+        let mut server = Task::empty("server");
+        let mut client = Task::empty("client");
+        let id = open_endpoint(&mut server, tid(1));
+        // Being able to send to another task's endpoint with the same ID
+        // doesn't count
+        give_endpoint(&mut client, tid(4), id);
+
+        assert!(matches!(send(&client, tid(2), &mut server, id, 0), SyscallResult::Err(KError::InvalidCapability)));
+        assert!(matches!(receive_endpoint(&mut server, id.value()), SyscallResult::Err(KError::NoMessages)));
+    }
+}
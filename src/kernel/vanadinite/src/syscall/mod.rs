@@ -6,6 +6,8 @@
 // obtain one at https://mozilla.org/MPL/2.0/.
 
 pub mod channel;
+pub mod endpoint;
+pub mod task;
 pub mod vmspace;
 
 use crate::{
@@ -16,19 +18,18 @@ use crate::{
         user::RawUserSlice,
     },
     scheduler::{Scheduler, CURRENT_TASK, SCHEDULER, TASKS},
-    task::TaskState,
     trap::TrapFrame,
-    utils,
 };
-use core::{convert::TryInto, num::NonZeroUsize};
+use core::{convert::TryInto, num::NonZeroUsize, sync::atomic::Ordering};
 use librust::{
-    error::{AccessError, KError},
+    error::{AccessError, ChannelArgumentError, KError},
     message::{Message, Recipient, Sender, SyscallRequest, SyscallResult},
     syscalls::{
         allocation::{AllocationOptions, DmaAllocationOptions, MemoryPermissions},
+        channel::{ReadyChannels, MAX_CHANNEL_LABEL_LEN, MAX_INITIAL_MESSAGE_BYTES, MAX_SHARED_MESSAGE_CHANNELS},
         Syscall,
     },
-    task::Tid,
+    task::{ExitReason, Tid},
 };
 
 pub fn handle(frame: &mut TrapFrame) {
@@ -63,11 +64,11 @@ fn do_syscall(msg: Message) -> SyscallResult<(Sender, Message), KError> {
     log::trace!("Doing syscall: {:?}", msg);
 
     let mut sender = Sender::kernel();
-    let task_lock = TASKS.get(CURRENT_TASK.get().unwrap()).unwrap();
-    let mut task_lock = task_lock.lock();
+    let current_task = TASKS.get(CURRENT_TASK.get().unwrap()).unwrap();
+    let mut task_lock = current_task.lock();
     let task = &mut *task_lock;
 
-    let syscall_req = SyscallRequest {
+    let mut syscall_req = SyscallRequest {
         syscall: match Syscall::from_usize(msg.contents[0]) {
             Some(syscall) => syscall,
             None => return SyscallResult::Err(KError::InvalidSyscall(msg.contents[0])),
@@ -75,16 +76,71 @@ fn do_syscall(msg: Message) -> SyscallResult<(Sender, Message), KError> {
         arguments: msg.contents[1..].try_into().unwrap(),
     };
 
+    // Any handle made with `dup_channel` can be used in place of the channel it
+    // was duplicated from, so swap it out for the original up front
+    let takes_channel = matches!(
+        syscall_req.syscall,
+        Syscall::CreateChannelMessage
+            | Syscall::SendChannelMessage
+            | Syscall::SendChannelMessageBlocking
+            | Syscall::SendChannelMessageShared
+            | Syscall::SendChannelRegion
+            | Syscall::LendChannelRegion
+            | Syscall::RecallChannelRegion
+            | Syscall::ReadChannel
+            | Syscall::RetireChannelMessage
+            | Syscall::RetireAndReply
+            | Syscall::ChannelStats
+            | Syscall::DumpChannel
+            | Syscall::CancelChannelMessage
+            | Syscall::TrimChannelMessage
+            | Syscall::GrantChannelCredits
+            | Syscall::SetChannelLabel
+            | Syscall::ChannelLabel
+            | Syscall::SpliceChannels
+    );
+
+    if takes_channel {
+        syscall_req.arguments[0] = channel::resolve_handle(task, syscall_req.arguments[0])?;
+    }
+
     let msg: Message = match syscall_req.syscall {
         Syscall::Exit => {
-            log::info!("Active process exited");
-            task.state = TaskState::Dead;
-            task.message_queue.clear();
+            // Tasks can only say whether they exited normally, anything else is
+            // up to the kernel to decide
+            let reason = match ExitReason::from_usize(syscall_req.arguments[0]) {
+                Some(ExitReason::Panicked) => ExitReason::Panicked,
+                _ => ExitReason::Exited,
+            };
+
+            log::info!("Active process exited: {:?}", reason);
+            task::mark_dead(task, reason, None);
 
+            // `schedule` never returns, so anything left on the stack is never
+            // dropped, and this would keep the task alive forever
             drop(task_lock);
+            drop(current_task);
 
             SCHEDULER.schedule()
         }
+        Syscall::Kill => {
+            let tid = match NonZeroUsize::new(syscall_req.arguments[0]) {
+                Some(tid) => Tid::new(tid),
+                None => return SyscallResult::Err(KError::InvalidArgument(0)),
+            };
+
+            if tid == CURRENT_TASK.get().unwrap() {
+                log::info!("Active process killed itself");
+                task::mark_dead(task, ExitReason::Killed, None);
+
+                drop(task_lock);
+                drop(current_task);
+
+                SCHEDULER.schedule()
+            }
+
+            Message::from(task::kill(task, tid)?)
+        }
         Syscall::Print => {
             let start = VirtualAddress::new(syscall_req.arguments[0]);
             let len = syscall_req.arguments[1];
@@ -167,12 +223,27 @@ fn do_syscall(msg: Message) -> SyscallResult<(Sender, Message), KError> {
 
             match size {
                 0 => return SyscallResult::Err(KError::InvalidArgument(0)),
+                // Lazily allocated pages are always zeroed, since they're only
+                // backed once they're first touched
+                _ if options & AllocationOptions::Lazy => {
+                    let allocated_at = task.memory_manager.alloc_lazy_region(
+                        None,
+                        page_size,
+                        page_size.pages_for(size),
+                        flags,
+                        AddressRegionKind::UserAllocated,
+                    );
+
+                    log::debug!("Allocated lazy memory at {:#p} for user process", allocated_at.start);
+
+                    Message::from(allocated_at.start.as_usize())
+                }
                 _ => {
                     let allocated_at = task.memory_manager.alloc_region(
                         None,
                         RegionDescription {
                             size: page_size,
-                            len: utils::round_up_to_next(size, page_size.to_byte_size()) / page_size.to_byte_size(),
+                            len: page_size.pages_for(size),
                             contiguous: false,
                             flags,
                             fill: if options & AllocationOptions::Zero {
@@ -190,14 +261,60 @@ fn do_syscall(msg: Message) -> SyscallResult<(Sender, Message), KError> {
                 }
             }
         }
+        // Every syscall reschedules on return, so there's nothing else to do
+        Syscall::Yield => Message::default(),
         Syscall::GetTid => (CURRENT_TASK.get().unwrap().value()).into(),
+        Syscall::TlsBase => Message::from(task.tls_base.map(|base| base.as_usize()).unwrap_or(0)),
+        // The task is current on this hart for as long as the syscall is being
+        // handled, so this agrees with `CURRENT_TASK`
+        Syscall::CurrentHart => Message::from(crate::HART_ID.get()),
+        Syscall::OnlineHarts => Message::from(crate::ONLINE_HARTS.load(Ordering::Acquire)),
+        Syscall::FaultStats => Message::from(task.fault_stats),
         Syscall::CreateChannel => {
+            // A zero TID accepts whichever pending request is the oldest
             let tid = match NonZeroUsize::new(syscall_req.arguments[0]) {
-                Some(tid) => tid,
-                None => return SyscallResult::Err(KError::InvalidArgument(0)),
+                Some(tid) => Tid::new(tid),
+                None => match task.incoming_channel_request.oldest() {
+                    Some(tid) => tid,
+                    None => return SyscallResult::Err(KError::InvalidOperation),
+                },
+            };
+
+            let initial_message = match syscall_req.arguments[3] {
+                0 => None,
+                start => {
+                    let len = syscall_req.arguments[4];
+                    if len > MAX_INITIAL_MESSAGE_BYTES {
+                        return SyscallResult::Err(KError::InvalidChannelArgument(
+                            3,
+                            ChannelArgumentError::MessageTooLarge,
+                        ));
+                    }
+
+                    let user_slice = RawUserSlice::readable(VirtualAddress::new(start), len);
+                    let user_slice = match unsafe { user_slice.validate(&task.memory_manager) } {
+                        Ok(slice) => slice,
+                        Err((addr, e)) => {
+                            log::error!("Bad memory from process: {:?}", e);
+                            return SyscallResult::Err(KError::InvalidAccess(AccessError::Read(addr.as_ptr())));
+                        }
+                    };
+
+                    Some(user_slice.with(|bytes| bytes.to_vec()))
+                }
             };
 
-            Message::from(channel::create_channel(task, Tid::new(tid))?)
+            // The TID is returned after the channel ID so that the caller
+            // knows who it accepted
+            let channel_id = channel::create_channel(
+                task,
+                tid,
+                syscall_req.arguments[1],
+                syscall_req.arguments[2],
+                initial_message.as_deref(),
+            )?;
+
+            Message::from((channel_id, tid.value()))
         }
         Syscall::CreateChannelMessage => {
             Message::from(channel::create_message(task, syscall_req.arguments[0], syscall_req.arguments[1])?)
@@ -207,18 +324,189 @@ fn do_syscall(msg: Message) -> SyscallResult<(Sender, Message), KError> {
             syscall_req.arguments[0],
             syscall_req.arguments[1],
             syscall_req.arguments[2],
+            syscall_req.arguments[3],
+            syscall_req.arguments[4] as u64,
         )?),
+        Syscall::SendChannelMessageBlocking => Message::from(channel::send_message_blocking(
+            task,
+            syscall_req.arguments[0],
+            syscall_req.arguments[1],
+            syscall_req.arguments[2],
+            syscall_req.arguments[3],
+            syscall_req.arguments[4] as u64,
+        )?),
+        Syscall::SendChannelMessageShared => {
+            let n_also_to = syscall_req.arguments[3];
+            if n_also_to > MAX_SHARED_MESSAGE_CHANNELS {
+                return SyscallResult::Err(KError::InvalidArgument(3));
+            }
+
+            let mut also_to = [0; MAX_SHARED_MESSAGE_CHANNELS];
+            for (resolved, &handle) in also_to.iter_mut().zip(&syscall_req.arguments[4..][..n_also_to]) {
+                *resolved = channel::resolve_handle(task, handle)?;
+            }
+
+            Message::from(channel::send_message_shared(
+                task,
+                syscall_req.arguments[0],
+                syscall_req.arguments[1],
+                syscall_req.arguments[2],
+                &also_to[..n_also_to],
+            )?)
+        }
+        Syscall::SendChannelRegion => Message::from(channel::send_region(
+            task,
+            syscall_req.arguments[0],
+            VirtualAddress::new(syscall_req.arguments[1]),
+            syscall_req.arguments[2],
+        )?),
+        Syscall::LendChannelRegion => Message::from(channel::lend_region(
+            task,
+            syscall_req.arguments[0],
+            VirtualAddress::new(syscall_req.arguments[1]),
+            syscall_req.arguments[2],
+        )?),
+        Syscall::RecallChannelRegion => {
+            Message::from(channel::recall_region(task, syscall_req.arguments[0], syscall_req.arguments[1])?)
+        }
         Syscall::ReadChannel => Message::from(channel::read_message(task, syscall_req.arguments[0])?),
+        Syscall::ReceiveAny => Message::from(channel::receive_any(task)?),
         Syscall::RetireChannelMessage => {
             Message::from(channel::retire_message(task, syscall_req.arguments[0], syscall_req.arguments[1])?)
         }
+        Syscall::RetireAndReply => Message::from(channel::retire_and_reply(
+            task,
+            syscall_req.arguments[0],
+            syscall_req.arguments[1],
+            syscall_req.arguments[2],
+        )?),
+        Syscall::ChannelStats => Message::from(channel::channel_stats(task, syscall_req.arguments[0])?),
+        Syscall::DumpChannel => Message::from(channel::dump_channel(task, syscall_req.arguments[0])?),
+        Syscall::SetChannelLabel => {
+            let len = syscall_req.arguments[2];
+            if len > MAX_CHANNEL_LABEL_LEN {
+                return SyscallResult::Err(KError::InvalidArgument(1));
+            }
+
+            let user_slice = RawUserSlice::readable(VirtualAddress::new(syscall_req.arguments[1]), len);
+            let user_slice = match unsafe { user_slice.validate(&task.memory_manager) } {
+                Ok(slice) => slice,
+                Err((addr, e)) => {
+                    log::error!("Bad memory from process: {:?}", e);
+                    return SyscallResult::Err(KError::InvalidAccess(AccessError::Read(addr.as_ptr())));
+                }
+            };
+
+            let label = user_slice.with(|bytes| bytes.to_vec());
+            let label = match core::str::from_utf8(&label) {
+                Ok(label) => label,
+                Err(_) => return SyscallResult::Err(KError::InvalidArgument(1)),
+            };
+
+            Message::from(channel::set_channel_label(task, syscall_req.arguments[0], label)?)
+        }
+        Syscall::ChannelLabel => {
+            let label = channel::channel_label(task, syscall_req.arguments[0])?.unwrap_or("");
+
+            let start = VirtualAddress::new(syscall_req.arguments[1]);
+            let user_slice = RawUserSlice::writable(start, syscall_req.arguments[2]);
+            let mut user_slice = match unsafe { user_slice.validate(&task.memory_manager) } {
+                Ok(slice) => slice,
+                Err((addr, e)) => {
+                    log::error!("Bad memory from process: {:?}", e);
+                    return SyscallResult::Err(KError::InvalidAccess(AccessError::Write(addr.as_mut_ptr())));
+                }
+            };
+
+            user_slice.with(|bytes| {
+                let n = bytes.len().min(label.len());
+                bytes[..n].copy_from_slice(&label.as_bytes()[..n]);
+            });
+
+            Message::from(label.len())
+        }
+        Syscall::CancelChannelMessage => {
+            Message::from(channel::cancel_message(task, syscall_req.arguments[0], syscall_req.arguments[1])?)
+        }
+        Syscall::TrimChannelMessage => Message::from(channel::trim_message(
+            task,
+            syscall_req.arguments[0],
+            syscall_req.arguments[1],
+            syscall_req.arguments[2],
+        )?),
+        Syscall::CreateEndpoint => Message::from(endpoint::create_endpoint(task)?),
+        Syscall::SendEndpoint => {
+            let tid = match NonZeroUsize::new(syscall_req.arguments[0]) {
+                Some(tid) => tid,
+                None => return SyscallResult::Err(KError::InvalidArgument(0)),
+            };
+
+            Message::from(endpoint::send_endpoint(
+                task,
+                Tid::new(tid),
+                syscall_req.arguments[1],
+                syscall_req.arguments[2..].try_into().unwrap(),
+            )?)
+        }
+        Syscall::ReceiveEndpoint => endpoint::receive_endpoint(task, syscall_req.arguments[0])?,
+        Syscall::GrantEndpoint => {
+            let tid = match NonZeroUsize::new(syscall_req.arguments[1]) {
+                Some(tid) => tid,
+                None => return SyscallResult::Err(KError::InvalidArgument(1)),
+            };
+
+            Message::from(endpoint::grant_endpoint(task, syscall_req.arguments[0], Tid::new(tid))?)
+        }
+        Syscall::CreateChannelPair => {
+            let (task_a, task_b) =
+                match (NonZeroUsize::new(syscall_req.arguments[0]), NonZeroUsize::new(syscall_req.arguments[1])) {
+                    (Some(task_a), Some(task_b)) => (task_a, task_b),
+                    (None, _) => return SyscallResult::Err(KError::InvalidArgument(0)),
+                    (_, None) => return SyscallResult::Err(KError::InvalidArgument(1)),
+                };
+
+            Message::from(channel::create_channel_pair(
+                task,
+                Tid::new(task_a),
+                Tid::new(task_b),
+                syscall_req.arguments[2],
+                syscall_req.arguments[3],
+            )?)
+        }
+        Syscall::GcChannels => Message::from(channel::gc_channels(task)?),
+        Syscall::DupChannel => Message::from(channel::dup_channel(task, syscall_req.arguments[0])?),
+        Syscall::CloseChannel => Message::from(channel::close_channel(task, syscall_req.arguments[0])?),
+        Syscall::SpliceChannels => {
+            let b = channel::resolve_handle(task, syscall_req.arguments[1])?;
+            Message::from(channel::splice_channels(task, syscall_req.arguments[0], b)?)
+        }
+        Syscall::GrantChannelCredits => {
+            Message::from(channel::grant_channel_credits(task, syscall_req.arguments[0], syscall_req.arguments[1])?)
+        }
+        Syscall::PollReadyChannels => Message::from(channel::poll_ready_channels(task).collect::<ReadyChannels>()),
         Syscall::RequestChannel => {
             let tid = match NonZeroUsize::new(syscall_req.arguments[0]) {
                 Some(tid) => tid,
                 None => return SyscallResult::Err(KError::InvalidArgument(0)),
             };
 
-            channel::request_channel(task, Tid::new(tid))?
+            Message::from(channel::request_channel(task, Tid::new(tid))?)
+        }
+        Syscall::AllowChannelRequests => {
+            let tid = match NonZeroUsize::new(syscall_req.arguments[0]) {
+                Some(tid) => tid,
+                None => return SyscallResult::Err(KError::InvalidArgument(0)),
+            };
+
+            Message::from(channel::allow_channel_requests(task, Tid::new(tid))?)
+        }
+        Syscall::DisallowChannelRequests => {
+            let tid = match NonZeroUsize::new(syscall_req.arguments[0]) {
+                Some(tid) => tid,
+                None => return SyscallResult::Err(KError::InvalidArgument(0)),
+            };
+
+            Message::from(channel::disallow_channel_requests(task, Tid::new(tid))?)
         }
         Syscall::AllocDmaMemory => {
             let size = syscall_req.arguments[0];
@@ -232,7 +520,7 @@ fn do_syscall(msg: Message) -> SyscallResult<(Sender, Message), KError> {
                         None,
                         RegionDescription {
                             size: page_size,
-                            len: utils::round_up_to_next(size, page_size.to_byte_size()) / page_size.to_byte_size(),
+                            len: page_size.pages_for(size),
                             contiguous: true,
                             flags: flags::VALID | flags::USER | flags::READ | flags::WRITE,
                             fill: if options & DmaAllocationOptions::ZERO {
@@ -270,6 +558,23 @@ fn do_syscall(msg: Message) -> SyscallResult<(Sender, Message), KError> {
             syscall_req.arguments[5],
             syscall_req.arguments[6],
         )?),
+        Syscall::RegisterFaultHandler => {
+            let handler = VirtualAddress::new(syscall_req.arguments[0]);
+
+            match handler.as_usize() {
+                0 => task.fault_handler = None,
+                _ => match task.memory_manager.page_flags(handler) {
+                    Some(flags) if flags & (flags::USER | flags::EXECUTE) => task.fault_handler = Some(handler),
+                    _ => return SyscallResult::Err(KError::InvalidArgument(0)),
+                },
+            }
+
+            // Registering a handler (again) re-arms it after a fault has been
+            // delivered
+            task.handling_fault = false;
+
+            Message::default()
+        }
     };
 
     SyscallResult::Ok((sender, msg))
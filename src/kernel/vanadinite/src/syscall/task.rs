@@ -0,0 +1,58 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2021 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use super::channel;
+use crate::{
+    scheduler::{self, Scheduler, CURRENT_TASK, SCHEDULER, TASKS},
+    task::{Task, TaskState},
+};
+use librust::{
+    error::KError,
+    message::SyscallResult,
+    task::{ExitReason, Tid},
+};
+
+/// Forcibly terminate the task `tid`, which must have been spawned by the
+/// current task. Killing the current task is handled by the caller since it
+/// never returns.
+pub fn kill(task: &mut Task, tid: Tid) -> SyscallResult<(), KError> {
+    let current_tid = CURRENT_TASK.get().unwrap();
+
+    let target = match TASKS.get(tid) {
+        Some(target) => target,
+        None => return SyscallResult::Err(KError::InvalidArgument(0)),
+    };
+
+    let mut target = target.lock();
+
+    if target.state.is_dead() {
+        return SyscallResult::Err(KError::InvalidArgument(0));
+    } else if target.parent != Some(current_tid) {
+        return SyscallResult::Err(KError::InvalidOperation);
+    }
+
+    log::info!("{:?} killed {:?}", current_tid, tid);
+
+    mark_dead(&mut target, ExitReason::Killed, Some((current_tid, task)));
+
+    drop(target);
+    SCHEDULER.dequeue(tid);
+    scheduler::retire_task(tid);
+
+    SyscallResult::Ok(())
+}
+
+/// Mark `dying` as dead with `reason` and tear down everything other tasks
+/// could be waiting on it for, so that they're woken to find out. `current` is
+/// the task performing the syscall when that isn't `dying` itself, which is
+/// already locked by the caller.
+pub fn mark_dead(dying: &mut Task, reason: ExitReason, current: Option<(Tid, &mut Task)>) {
+    dying.state = TaskState::Dead(reason);
+    dying.message_queue.clear();
+    dying.incoming_channel_request.clear();
+    channel::close_all_channels(dying, current);
+}
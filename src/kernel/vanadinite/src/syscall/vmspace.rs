@@ -12,11 +12,12 @@ use crate::{
         paging::{flags, PageSize, VirtualAddress},
     },
     scheduler::{Scheduler, CURRENT_TASK, SCHEDULER},
-    task::{Context, Task},
+    task::{alloc_tls_region, Context, Task, DEFAULT_CHANNEL_LIMIT, DEFAULT_SHARED_MEMORY_QUOTA},
     trap::GeneralRegisters,
     utils::{self, Units},
 };
 use alloc::vec::Vec;
+use core::sync::atomic::Ordering;
 use librust::{
     error::KError,
     message::SyscallResult,
@@ -105,12 +106,20 @@ pub fn alloc_vmspace_object(
         },
     );
 
-    let range = task.memory_manager.apply_shared_region(
+    let range = match task.memory_manager.apply_shared_region(
         None,
         flags::USER | flags::VALID | flags::READ | flags::WRITE,
         region,
         AddressRegionKind::UserAllocated,
-    );
+    ) {
+        Ok(range) => range,
+        Err(_) => {
+            // The task's address space is full, so don't leave the region
+            // behind in the vmspace where it can't be reached by anyone
+            object.memory_manager.dealloc_region(at.start);
+            return SyscallResult::Err(KError::QuotaExceeded);
+        }
+    };
 
     object.inprocess_mappings.push(range.start);
     log::debug!("added {:#p} to task vmspace", range.start);
@@ -143,24 +152,42 @@ pub fn spawn_vmspace(
         a1,
         a2
     );
-    log::debug!("Memory map:\n{:#?}", object.memory_manager.address_map_debug());
+    let mut memory_manager = object.memory_manager;
+    let tls_base = alloc_tls_region(&mut memory_manager);
+
+    log::debug!("Memory map:\n{:#?}", memory_manager.address_map_debug());
 
     let new_task = Task {
         name: alloc::format!("userspace allocated task by {:?}", CURRENT_TASK.get().unwrap()).into_boxed_str(),
+        parent: CURRENT_TASK.get(),
         context: Context {
             pc,
             gp_regs: GeneralRegisters { a0, a1, a2, sp, tp, ..Default::default() },
             fp_regs: Default::default(),
         },
-        memory_manager: object.memory_manager,
+        memory_manager,
         state: crate::task::TaskState::Running,
         message_queue: Default::default(),
+        waiting_for_message: false,
         promiscuous: true,
         incoming_channel_request: Default::default(),
+        channel_allowlist: Default::default(),
         channels: Default::default(),
+        channel_next_id: 0,
+        channel_dups: Default::default(),
+        channel_limit: DEFAULT_CHANNEL_LIMIT.load(Ordering::Relaxed),
+        lent_regions: Default::default(),
+        endpoints: Default::default(),
+        endpoint_next_id: 0,
         vmspace_next_id: 0,
         vmspace_objects: Default::default(),
         cspace: CapabilitySpace::new(),
+        fault_handler: None,
+        handling_fault: false,
+        fault_stats: Default::default(),
+        shared_memory_bytes: 0,
+        shared_memory_quota: DEFAULT_SHARED_MEMORY_QUOTA,
+        tls_base: Some(tls_base),
     };
 
     for region in object.inprocess_mappings {
@@ -15,7 +15,11 @@ use crate::{
         },
     },
     platform::FDT,
-    syscall::{channel::UserspaceChannel, vmspace::VmspaceObject},
+    syscall::{
+        channel::{ChannelRequests, LentRegion, UserspaceChannel},
+        endpoint::Endpoint,
+        vmspace::VmspaceObject,
+    },
     trap::{FloatingPointRegisters, GeneralRegisters},
     utils::{round_up_to_next, Units},
 };
@@ -24,12 +28,17 @@ use alloc::{
     collections::{BTreeMap, BTreeSet, VecDeque},
     vec::Vec,
 };
+use core::sync::atomic::{AtomicUsize, Ordering};
 use elf64::{Elf, ProgramSegmentType, Relocation};
 use fdt::Fdt;
 use librust::{
     message::{Message, Sender},
-    syscalls::{channel::ChannelId, vmspace::VmspaceObjectId},
-    task::Tid,
+    syscalls::{
+        channel::{ChannelId, MessageId},
+        endpoint::EndpointId,
+        vmspace::VmspaceObjectId,
+    },
+    task::{ExitReason, FaultStats, Tid},
 };
 
 #[derive(Debug)]
@@ -74,18 +83,49 @@ pub struct Context {
     pub pc: usize,
 }
 
+/// The default limit on [`Task::shared_memory_bytes`]
+pub const DEFAULT_SHARED_MEMORY_QUOTA: usize = 64 * 1024 * 1024;
+
+/// The limit on how many channels a new task may have open at once, which can
+/// be changed with the `channel-limit` kernel argument
+pub static DEFAULT_CHANNEL_LIMIT: AtomicUsize = AtomicUsize::new(256);
+
 pub struct Task {
     pub name: Box<str>,
+    pub parent: Option<Tid>,
     pub context: Context,
     pub memory_manager: MemoryManager,
     pub state: TaskState,
     pub message_queue: VecDeque<(Sender, Message)>,
+    /// Whether the task is blocked in `receive_any` until a message arrives on
+    /// any of its channels
+    pub waiting_for_message: bool,
     pub promiscuous: bool,
-    pub incoming_channel_request: BTreeSet<Tid>,
+    pub incoming_channel_request: ChannelRequests,
+    pub channel_allowlist: BTreeSet<Tid>,
     pub channels: BTreeMap<ChannelId, UserspaceChannel>,
+    pub channel_next_id: usize,
+    /// Handles made with `dup_channel`, and the channel each one refers to
+    pub channel_dups: BTreeMap<ChannelId, ChannelId>,
+    /// The most entries `channels` may have
+    pub channel_limit: usize,
+    /// Regions lent out with `lend_region`, by the channel and message they
+    /// were sent as
+    pub lent_regions: BTreeMap<(ChannelId, MessageId), LentRegion>,
+    pub endpoints: BTreeMap<EndpointId, Endpoint>,
+    pub endpoint_next_id: usize,
     pub vmspace_objects: BTreeMap<VmspaceObjectId, VmspaceObject>,
     pub vmspace_next_id: usize,
     pub cspace: CapabilitySpace,
+    pub fault_handler: Option<VirtualAddress>,
+    pub handling_fault: bool,
+    pub fault_stats: FaultStats,
+    /// Bytes of memory currently backing channel messages mapped into the task
+    pub shared_memory_bytes: usize,
+    pub shared_memory_quota: usize,
+    /// The start of the task's thread-local storage region, which userspace
+    /// runtimes can query to find somewhere to place their TLS
+    pub tls_base: Option<VirtualAddress>,
 }
 
 impl Task {
@@ -254,7 +294,14 @@ impl Task {
                 },
             );
 
-            tls_base.add(segment_load_offset).as_usize()
+            (tls_base, tls_base.add(segment_load_offset).as_usize())
+        });
+
+        // Runtimes expect somewhere to put their thread-local data even if the
+        // program itself doesn't have any
+        let (tls_base, tp) = tls.unwrap_or_else(|| {
+            let tls_base = alloc_tls_region(&mut memory_manager);
+            (tls_base, tls_base.as_usize())
         });
 
         // We guard the stack on both ends, though a stack underflow is
@@ -324,7 +371,7 @@ impl Task {
             pc: pc.as_usize(),
             gp_regs: GeneralRegisters {
                 sp: sp.as_usize(),
-                tp: tls.unwrap_or(0),
+                tp,
                 a0,
                 a1,
                 a2: fdt_loc.start.as_usize(),
@@ -335,29 +382,110 @@ impl Task {
 
         Self {
             name: Box::from(name),
+            parent: None,
             context,
             memory_manager,
             state: TaskState::Running,
+            waiting_for_message: false,
             promiscuous: true,
-            incoming_channel_request: BTreeSet::new(),
+            incoming_channel_request: ChannelRequests::default(),
+            channel_allowlist: BTreeSet::new(),
             channels: BTreeMap::new(),
+            channel_next_id: 0,
+            channel_dups: BTreeMap::new(),
+            channel_limit: DEFAULT_CHANNEL_LIMIT.load(Ordering::Relaxed),
+            lent_regions: BTreeMap::new(),
+            endpoints: BTreeMap::new(),
+            endpoint_next_id: 0,
             message_queue: VecDeque::new(),
             vmspace_objects: BTreeMap::new(),
             vmspace_next_id: 0,
             cspace,
+            fault_handler: None,
+            handling_fault: false,
+            fault_stats: FaultStats::default(),
+            shared_memory_bytes: 0,
+            shared_memory_quota: DEFAULT_SHARED_MEMORY_QUOTA,
+            tls_base: Some(tls_base),
         }
     }
 }
 
+#[cfg(test)]
+impl Task {
+    /// A task with nothing loaded into it, for testing things that only touch
+    /// the task's own bookkeeping
+    pub fn empty(name: &str) -> Self {
+        Self {
+            name: Box::from(name),
+            parent: None,
+            context: Context {
+                gp_regs: GeneralRegisters::default(),
+                fp_regs: FloatingPointRegisters::default(),
+                pc: 0,
+            },
+            memory_manager: MemoryManager::new(),
+            state: TaskState::Running,
+            waiting_for_message: false,
+            promiscuous: true,
+            incoming_channel_request: ChannelRequests::default(),
+            channel_allowlist: BTreeSet::new(),
+            channels: BTreeMap::new(),
+            channel_next_id: 0,
+            channel_dups: BTreeMap::new(),
+            channel_limit: DEFAULT_CHANNEL_LIMIT.load(Ordering::Relaxed),
+            lent_regions: BTreeMap::new(),
+            endpoints: BTreeMap::new(),
+            endpoint_next_id: 0,
+            message_queue: VecDeque::new(),
+            vmspace_objects: BTreeMap::new(),
+            vmspace_next_id: 0,
+            cspace: CapabilitySpace::new(),
+            fault_handler: None,
+            handling_fault: false,
+            fault_stats: FaultStats::default(),
+            shared_memory_bytes: 0,
+            shared_memory_quota: DEFAULT_SHARED_MEMORY_QUOTA,
+            tls_base: None,
+        }
+    }
+}
+
+/// Allocate an empty page of thread-local storage for a task, returning the
+/// start of the region
+pub fn alloc_tls_region(memory_manager: &mut MemoryManager) -> VirtualAddress {
+    memory_manager
+        .alloc_region(
+            None,
+            RegionDescription {
+                size: PageSize::Kilopage,
+                len: 1,
+                contiguous: false,
+                flags: USER | READ | WRITE | VALID,
+                fill: FillOption::Zeroed,
+                kind: AddressRegionKind::Tls,
+            },
+        )
+        .start
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum TaskState {
     Blocked,
-    Dead,
+    /// The task has stopped running for good, and won't be scheduled again
+    Dead(ExitReason),
     Running,
 }
 
 impl TaskState {
     pub fn is_dead(self) -> bool {
-        matches!(self, TaskState::Dead)
+        matches!(self, TaskState::Dead(_))
+    }
+
+    pub fn exit_reason(self) -> Option<ExitReason> {
+        match self {
+            TaskState::Dead(reason) => Some(reason),
+            _ => None,
+        }
     }
 }
@@ -56,8 +56,8 @@ pub extern "C" fn ktest(hart_id: usize, fdt: *const u8) -> ! {
         }
     }
 
-    let n_cpus = fdt.cpus().count();
-    N_CPUS.store(n_cpus, Ordering::Release);
+    let max_hart_id = fdt.cpus().map(|cpu| cpu.ids().first()).max().unwrap_or(0);
+    N_CPUS.store(max_hart_id + 1, Ordering::Release);
 
     if let Some(ic) = fdt.find_compatible(Plic::compatible_with()) {
         let reg = ic.reg().unwrap().next().unwrap();
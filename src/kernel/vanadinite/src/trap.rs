@@ -9,13 +9,17 @@ use crate::{
     csr::sstatus,
     interrupts::{isr::isr_entry, PLIC},
     mem::{
-        manager::AddressRegion,
+        manager::{AddressRegion, MemoryManager},
         paging::{flags, VirtualAddress},
         region::MemoryRegion,
     },
     scheduler::{Scheduler, CURRENT_TASK, SCHEDULER, TASKS},
     syscall,
-    task::TaskState,
+    task::Task,
+};
+use librust::{
+    message::{KernelNotification, Sender},
+    task::{ExitReason, FaultStats},
 };
 
 #[derive(Debug, Clone, Copy, Default)]
@@ -189,7 +193,13 @@ pub extern "C" fn trap_handler(regs: &mut TrapFrame, sepc: usize, scause: usize,
 
     let trap_kind = Trap::from_cause(scause);
     match trap_kind {
-        Trap::SupervisorTimerInterrupt => {
+        Trap::SupervisorTimerInterrupt | Trap::SupervisorSoftwareInterrupt => {
+            // Software interrupts are IPIs from other harts letting us know
+            // there might be new work to do
+            if let Trap::SupervisorSoftwareInterrupt = trap_kind {
+                crate::csr::sip::clear_ssip();
+            }
+
             if CURRENT_TASK.get().is_some() {
                 let lock = TASKS.active_on_cpu().unwrap();
                 let mut lock = lock.lock();
@@ -241,36 +251,9 @@ pub extern "C" fn trap_handler(regs: &mut TrapFrame, sepc: usize, scause: usize,
                 false => {
                     let active_task_lock = TASKS.active_on_cpu().unwrap();
                     let mut active_task = active_task_lock.lock();
-                    let memory_manager = &mut active_task.memory_manager;
-
-                    //log::info!("{:#?}", memory_manager.region_for(stval));
-
-                    let valid = match memory_manager.region_for(stval) {
-                        None | Some(AddressRegion { region: None, .. }) => false,
-                        Some(AddressRegion { region: Some(MemoryRegion::GuardPage), .. }) => {
-                            log::error!("Process hit a guard page, stack overflow?");
-                            false
-                        }
-                        _ => match trap_kind {
-                            Trap::LoadPageFault | Trap::InstructionPageFault => {
-                                match memory_manager.page_flags(stval) {
-                                    Some(flags) => {
-                                        (flags & flags::READ)
-                                            && memory_manager.modify_page_flags(stval, |f| f | flags::ACCESSED)
-                                    }
-                                    None => false,
-                                }
-                            }
-                            Trap::StorePageFault => match memory_manager.page_flags(stval) {
-                                Some(flags) => {
-                                    (flags & flags::WRITE)
-                                        && memory_manager
-                                            .modify_page_flags(stval, |f| f | flags::DIRTY | flags::ACCESSED)
-                                }
-                                None => false,
-                            },
-                            _ => unreachable!(),
-                        },
+                    let valid = {
+                        let task = &mut *active_task;
+                        resolve_user_page_fault(&mut task.memory_manager, &mut task.fault_stats, trap_kind, stval)
                     };
 
                     match valid {
@@ -279,13 +262,21 @@ pub extern "C" fn trap_handler(regs: &mut TrapFrame, sepc: usize, scause: usize,
                             sepc
                         }
                         false => {
-                            log::error!(
-                                "Process {:?} died to a {:?} @ {:#p}",
-                                CURRENT_TASK.get().unwrap(),
-                                trap_kind,
-                                stval
-                            );
-                            active_task.state = TaskState::Dead;
+                            active_task.context.gp_regs = regs.registers;
+                            match deliver_user_fault(&mut active_task, stval, scause, sepc) {
+                                true => log::debug!(
+                                    "Delivered {:?} @ {:#p} to fault handler of {:?}",
+                                    trap_kind,
+                                    stval,
+                                    CURRENT_TASK.get().unwrap(),
+                                ),
+                                false => log::error!(
+                                    "Process {:?} died to a {:?} @ {:#p}",
+                                    CURRENT_TASK.get().unwrap(),
+                                    trap_kind,
+                                    stval
+                                ),
+                            }
 
                             drop(active_task);
                             drop(active_task_lock);
@@ -300,6 +291,85 @@ pub extern "C" fn trap_handler(regs: &mut TrapFrame, sepc: usize, scause: usize,
     }
 }
 
+/// Try to resolve a page fault on a user address without involving the task,
+/// which is possible when the access is allowed and the page only needs to be
+/// lazily backed, or to have its accessed or dirty bits set. The fault is counted in `stats` as minor if it
+/// was resolved and as a protection fault otherwise.
+fn resolve_user_page_fault(
+    memory_manager: &mut MemoryManager,
+    stats: &mut FaultStats,
+    trap_kind: Trap,
+    stval: VirtualAddress,
+) -> bool {
+    let valid = match memory_manager.region_for(stval) {
+        None | Some(AddressRegion { region: None, .. }) => false,
+        Some(AddressRegion { region: Some(MemoryRegion::GuardPage), .. }) => {
+            log::error!("Process hit a guard page, stack overflow?");
+            false
+        }
+        _ => {
+            // Lazily allocated pages are only backed once they're first touched,
+            // after which the access is checked like any other
+            memory_manager.fill_lazy_page(stval);
+
+            match trap_kind {
+                Trap::LoadPageFault | Trap::InstructionPageFault => match memory_manager.page_flags(stval) {
+                    Some(flags) => {
+                        (flags & flags::READ) && memory_manager.modify_page_flags(stval, |f| f | flags::ACCESSED)
+                    }
+                    None => false,
+                },
+                Trap::StorePageFault => match memory_manager.page_flags(stval) {
+                    Some(flags) => {
+                        (flags & flags::WRITE)
+                            && memory_manager.modify_page_flags(stval, |f| f | flags::DIRTY | flags::ACCESSED)
+                    }
+                    None => false,
+                },
+                _ => unreachable!(),
+            }
+        }
+    };
+
+    match valid {
+        true => stats.minor += 1,
+        false => stats.protection += 1,
+    }
+
+    valid
+}
+
+/// Hand a fault the kernel couldn't resolve to the task's fault handler,
+/// which is entered with the faulting address, cause, and PC in `a0`-`a2`.
+/// Tasks without a handler, or which fault again while still handling one,
+/// are marked dead instead. Returns whether the fault was delivered.
+fn deliver_user_fault(task: &mut Task, stval: VirtualAddress, scause: usize, sepc: usize) -> bool {
+    match task.fault_handler {
+        // Faulting while already handling a fault would just loop, so only
+        // deliver the first one
+        Some(handler) if !task.handling_fault => {
+            task.handling_fault = true;
+            task.context.gp_regs.a0 = stval.as_usize();
+            task.context.gp_regs.a1 = scause;
+            task.context.gp_regs.a2 = sepc;
+            task.context.pc = handler.as_usize();
+
+            // The handler is what gets the task away from the faulting
+            // instruction, but it's a bare function with no access to the
+            // task's state, so the fault is also queued for whatever message
+            // loop the handler hands off to
+            let notification = KernelNotification::Fault { addr: stval.as_usize(), cause: scause, pc: sepc };
+            task.message_queue.push_front((Sender::kernel(), notification.into()));
+
+            true
+        }
+        _ => {
+            syscall::task::mark_dead(task, ExitReason::Faulted, None);
+            false
+        }
+    }
+}
+
 /// # Safety
 /// nice try
 #[naked]
@@ -469,3 +539,134 @@ extern "C" fn save_fp_registers(fp_regs: &mut FloatingPointRegisters) {
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mem::{
+            manager::{AddressRegionKind, FillOption, RegionDescription},
+            paging::PageSize,
+        },
+        task::TaskState,
+        utils::Units,
+    };
+
+    #[test]
+    fn first_touch_of_each_page_is_one_minor_fault() {
+        const N_PAGES: usize = 4;
+
+        let mut manager = MemoryManager::new();
+        let mut stats = FaultStats::default();
+        let range = manager.alloc_region(
+            None,
+            RegionDescription {
+                size: PageSize::Kilopage,
+                len: N_PAGES,
+                contiguous: false,
+                flags: flags::READ | flags::WRITE | flags::USER | flags::VALID,
+                fill: FillOption::Zeroed,
+                kind: AddressRegionKind::UserAllocated,
+            },
+        );
+
+        for page in 0..N_PAGES {
+            let addr = range.start.add(page * 4.kib());
+            assert!(resolve_user_page_fault(&mut manager, &mut stats, Trap::LoadPageFault, addr));
+            assert!(manager.page_flags(addr).unwrap() & flags::ACCESSED);
+        }
+
+        assert_eq!((stats.minor, stats.protection), (N_PAGES, 0));
+    }
+
+    #[test]
+    fn touching_lazy_pages_backs_them() {
+        const N_PAGES: usize = 8;
+
+        let mut manager = MemoryManager::new();
+        let mut stats = FaultStats::default();
+        let range = manager.alloc_lazy_region(
+            None,
+            PageSize::Kilopage,
+            N_PAGES,
+            flags::READ | flags::WRITE | flags::USER | flags::VALID,
+            AddressRegionKind::UserAllocated,
+        );
+
+        // Only every other page is touched, and none of them are backed yet
+        for page in (0..N_PAGES).step_by(2) {
+            let addr = range.start.add(page * 4.kib() + 8);
+            assert!(manager.page_flags(addr).is_none());
+            assert!(resolve_user_page_fault(&mut manager, &mut stats, Trap::StorePageFault, addr));
+            assert!(manager.page_flags(addr).unwrap() & flags::DIRTY);
+
+            let phys = manager.resolve(addr).unwrap();
+            let page = unsafe { core::slice::from_raw_parts(crate::mem::phys2virt(phys).as_ptr(), 4096) };
+            assert!(page.iter().all(|&byte| byte == 0));
+        }
+
+        assert_eq!((stats.minor, stats.protection), (N_PAGES / 2, 0));
+        assert!(manager.page_flags(range.start.add(4.kib())).is_none());
+
+        // Untouched pages have nothing to unmap
+        match manager.dealloc_region(range.start) {
+            MemoryRegion::Lazy { backing, .. } => assert_eq!(backing.len(), N_PAGES / 2),
+            region => panic!("expected a lazy region, got {:?}", region),
+        }
+    }
+
+    #[test]
+    fn disallowed_access_is_a_protection_fault() {
+        let mut manager = MemoryManager::new();
+        let mut stats = FaultStats::default();
+        let range = manager.alloc_region(
+            None,
+            RegionDescription {
+                size: PageSize::Kilopage,
+                len: 1,
+                contiguous: false,
+                flags: flags::READ | flags::USER | flags::VALID,
+                fill: FillOption::Zeroed,
+                kind: AddressRegionKind::ReadOnly,
+            },
+        );
+
+        assert!(!resolve_user_page_fault(&mut manager, &mut stats, Trap::StorePageFault, range.start));
+        assert!(!resolve_user_page_fault(&mut manager, &mut stats, Trap::LoadPageFault, VirtualAddress::new(0)));
+        assert_eq!((stats.minor, stats.protection), (0, 2));
+    }
+
+    #[test]
+    fn fault_handler_gets_the_fault() {
+        let mut task = Task::empty("faulty");
+        task.fault_handler = Some(VirtualAddress::new(0x4000));
+        let addr = VirtualAddress::new(0xdead000);
+
+        assert!(deliver_user_fault(&mut task, addr, Trap::LoadPageFault as usize, 0x1234));
+        assert!(matches!(task.state, TaskState::Running));
+        assert!(task.handling_fault);
+        assert_eq!(task.context.pc, 0x4000);
+        let regs = &task.context.gp_regs;
+        assert_eq!((regs.a0, regs.a1, regs.a2), (0xdead000, Trap::LoadPageFault as usize, 0x1234));
+
+        let (sender, notification) = task.message_queue.pop_front().unwrap();
+        assert_eq!(sender, Sender::kernel());
+        assert!(matches!(
+            KernelNotification::from(notification),
+            KernelNotification::Fault { addr: 0xdead000, cause: 13, pc: 0x1234 }
+        ));
+    }
+
+    #[test]
+    fn fault_while_handling_fault_kills_task() {
+        let mut task = Task::empty("faulty");
+        let handler = VirtualAddress::new(0x4000);
+        task.fault_handler = Some(handler);
+
+        assert!(deliver_user_fault(&mut task, VirtualAddress::new(0x1000), Trap::StorePageFault as usize, 0x1234));
+        // The handler itself faults before the task recovers
+        assert!(!deliver_user_fault(&mut task, handler, Trap::InstructionPageFault as usize, 0x4000));
+        assert!(matches!(task.state, TaskState::Dead(ExitReason::Faulted)));
+        assert!(task.message_queue.is_empty());
+    }
+}
@@ -13,6 +13,16 @@ pub const INVALID_RECIPIENT: usize = 3;
 pub const INVALID_SYSCALL: usize = 4;
 pub const INVALID_ARGUMENT: usize = 5;
 pub const NO_MESSAGES: usize = 6;
+pub const INVALID_OPERATION: usize = 7;
+pub const INVALID_CAPABILITY: usize = 8;
+pub const CHANNEL_FULL: usize = 9;
+pub const QUOTA_EXCEEDED: usize = 10;
+pub const CHANNEL_CLOSED: usize = 11;
+pub const RECIPIENT_DEAD: usize = 12;
+pub const CONNECTION_REFUSED: usize = 13;
+pub const CHANNEL_LIMIT_REACHED: usize = 14;
+pub const RECIPIENT_FULL: usize = 15;
+pub const ADDRESS_SPACE_EXHAUSTED: usize = 16;
 
 pub const IS_KERROR: usize = 1;
 
@@ -23,7 +33,27 @@ pub enum KError {
     InvalidRecipient,
     InvalidSyscall(usize),
     InvalidArgument(usize),
+    InvalidChannelArgument(usize, ChannelArgumentError),
     NoMessages,
+    InvalidOperation,
+    InvalidCapability,
+    ChannelFull,
+    QuotaExceeded,
+    /// The other end of the channel has gone away and there's nothing left on
+    /// it to read
+    ChannelClosed,
+    /// The task exists but has died
+    RecipientDead,
+    /// The task isn't accepting channel requests from the current task
+    ConnectionRefused,
+    /// One of the tasks already has as many channels open as it's allowed
+    ChannelLimitReached,
+    /// The recipient has no room left for the message, which is left with the
+    /// sender so it can be sent again later
+    RecipientFull,
+    /// There's no free range in the task's address space large enough to map
+    /// the region into
+    AddressSpaceExhausted,
 }
 
 impl From<Message> for KError {
@@ -32,18 +62,68 @@ impl From<Message> for KError {
             const { INVALID_MESSAGE } => Self::InvalidMessage,
             const { INVALID_RECIPIENT } => Self::InvalidRecipient,
             const { INVALID_SYSCALL } => Self::InvalidSyscall(msg.contents[1]),
-            const { INVALID_ARGUMENT } => Self::InvalidArgument(msg.contents[1]),
+            const { INVALID_ARGUMENT } => match ChannelArgumentError::from_usize(msg.contents[2]) {
+                Some(reason) => Self::InvalidChannelArgument(msg.contents[1], reason),
+                None => Self::InvalidArgument(msg.contents[1]),
+            },
             const { INVALID_ACCESS } => Self::InvalidAccess(match msg.contents[1] {
                 0 => AccessError::Read(msg.contents[2] as _),
                 1 => AccessError::Write(msg.contents[2] as _),
                 _ => unreachable!(),
             }),
             const { NO_MESSAGES } => Self::NoMessages,
+            const { INVALID_OPERATION } => Self::InvalidOperation,
+            const { INVALID_CAPABILITY } => Self::InvalidCapability,
+            const { CHANNEL_FULL } => Self::ChannelFull,
+            const { QUOTA_EXCEEDED } => Self::QuotaExceeded,
+            const { CHANNEL_CLOSED } => Self::ChannelClosed,
+            const { RECIPIENT_DEAD } => Self::RecipientDead,
+            const { CONNECTION_REFUSED } => Self::ConnectionRefused,
+            const { CHANNEL_LIMIT_REACHED } => Self::ChannelLimitReached,
+            const { RECIPIENT_FULL } => Self::RecipientFull,
+            const { ADDRESS_SPACE_EXHAUSTED } => Self::AddressSpaceExhausted,
             _ => unreachable!(),
         }
     }
 }
 
+/// The reason a channel syscall argument was rejected, encoded after the
+/// argument index so that it can be decoded as a plain
+/// [`KError::InvalidArgument`] when the reason isn't needed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum ChannelArgumentError {
+    /// Channels can't be opened between a task and itself
+    SameTask = 1,
+    /// The channel ID doesn't belong to the task
+    UnknownChannel = 2,
+    /// The message ID isn't pending on the channel
+    UnknownMessage = 3,
+    /// The message is larger than the channel allows
+    MessageTooLarge = 4,
+    /// The message length is larger than the region backing it
+    LengthExceedsRegion = 5,
+    /// The region isn't one that can be sent over a channel
+    InvalidRegion = 6,
+    /// The length can't be zero
+    ZeroLength = 7,
+}
+
+impl ChannelArgumentError {
+    pub fn from_usize(n: usize) -> Option<Self> {
+        match n {
+            1 => Some(Self::SameTask),
+            2 => Some(Self::UnknownChannel),
+            3 => Some(Self::UnknownMessage),
+            4 => Some(Self::MessageTooLarge),
+            5 => Some(Self::LengthExceedsRegion),
+            6 => Some(Self::InvalidRegion),
+            7 => Some(Self::ZeroLength),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug)]
 #[repr(C, usize)]
 pub enum AccessError {
@@ -53,3 +133,50 @@ pub enum AccessError {
 
 pub const ACCESS_ERROR_READ: usize = 0;
 pub const ACCESS_ERROR_WRITE: usize = 1;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(e: KError) -> KError {
+        KError::from(Message::from(e))
+    }
+
+    #[test]
+    fn plain_argument_error_round_trips() {
+        let msg = Message::from(KError::InvalidArgument(3));
+        assert_eq!(msg.contents[0], INVALID_ARGUMENT);
+        assert_eq!(msg.contents[1], 3);
+        assert_eq!(msg.contents[2], 0);
+
+        assert!(matches!(KError::from(msg), KError::InvalidArgument(3)));
+    }
+
+    #[test]
+    fn channel_argument_error_round_trips() {
+        let reasons = [
+            ChannelArgumentError::SameTask,
+            ChannelArgumentError::UnknownChannel,
+            ChannelArgumentError::UnknownMessage,
+            ChannelArgumentError::MessageTooLarge,
+            ChannelArgumentError::LengthExceedsRegion,
+            ChannelArgumentError::InvalidRegion,
+            ChannelArgumentError::ZeroLength,
+        ];
+
+        for (idx, &reason) in reasons.iter().enumerate() {
+            match round_trip(KError::InvalidChannelArgument(idx, reason)) {
+                KError::InvalidChannelArgument(i, r) => assert_eq!((i, r), (idx, reason)),
+                e => panic!("{:?} decoded as {:?}", reason, e),
+            }
+        }
+    }
+
+    #[test]
+    fn channel_argument_error_reads_as_plain_argument_error() {
+        let msg = Message::from(KError::InvalidChannelArgument(1, ChannelArgumentError::UnknownMessage));
+        assert_eq!(msg.contents[0], INVALID_ARGUMENT);
+        assert_eq!(msg.contents[1], 1);
+        assert_eq!(msg.contents[2], ChannelArgumentError::UnknownMessage as usize);
+    }
+}
@@ -8,7 +8,7 @@
 use crate::{
     error::{self, AccessError, KError},
     syscalls::{channel::ChannelId, Syscall},
-    task::Tid,
+    task::{ExitReason, Tid},
 };
 use core::{convert::TryInto, num::NonZeroUsize};
 
@@ -142,7 +142,30 @@ impl From<KError> for Message {
             KError::InvalidArgument(idx) => {
                 Self { contents: [error::INVALID_ARGUMENT, idx, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] }
             }
+            KError::InvalidChannelArgument(idx, reason) => {
+                Self { contents: [error::INVALID_ARGUMENT, idx, reason as usize, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] }
+            }
             KError::NoMessages => Self { contents: [error::NO_MESSAGES, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] },
+            KError::InvalidOperation => {
+                Self { contents: [error::INVALID_OPERATION, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] }
+            }
+            KError::InvalidCapability => {
+                Self { contents: [error::INVALID_CAPABILITY, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] }
+            }
+            KError::ChannelFull => Self { contents: [error::CHANNEL_FULL, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] },
+            KError::QuotaExceeded => Self { contents: [error::QUOTA_EXCEEDED, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] },
+            KError::ChannelClosed => Self { contents: [error::CHANNEL_CLOSED, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] },
+            KError::RecipientDead => Self { contents: [error::RECIPIENT_DEAD, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] },
+            KError::ConnectionRefused => {
+                Self { contents: [error::CONNECTION_REFUSED, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] }
+            }
+            KError::ChannelLimitReached => {
+                Self { contents: [error::CHANNEL_LIMIT_REACHED, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] }
+            }
+            KError::RecipientFull => Self { contents: [error::RECIPIENT_FULL, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] },
+            KError::AddressSpaceExhausted => {
+                Self { contents: [error::ADDRESS_SPACE_EXHAUSTED, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] }
+            }
         }
     }
 }
@@ -328,6 +351,17 @@ impl Sender {
     pub fn is_task(self) -> bool {
         !self.is_kernel()
     }
+
+    /// The task which sent the message, or `None` if it came from the kernel
+    pub fn as_task(self) -> Option<Tid> {
+        NonZeroUsize::new(self.0).map(Tid::new)
+    }
+}
+
+impl PartialEq<Tid> for Sender {
+    fn eq(&self, other: &Tid) -> bool {
+        self.0 == other.value()
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -365,16 +399,20 @@ impl Recipient {
 pub enum KernelNotification {
     ChannelRequest(Tid),
     ChannelOpened(ChannelId),
-    ChannelRequestDenied,
     InterruptOccurred(usize),
     NewChannelMessage(ChannelId),
+    Fault { addr: usize, cause: usize, pc: usize },
+    ChannelCreditsGranted { channel: ChannelId, credits: usize },
+    ChannelClosed { channel: ChannelId, reason: Option<ExitReason> },
 }
 
 pub const NOTIFICATION_CHANNEL_REQUEST: usize = 0;
 pub const NOTIFICATION_CHANNEL_OPENED: usize = 1;
-pub const NOTIFICATION_CHANNEL_REQUEST_DENIED: usize = 2;
 pub const NOTIFICATION_INTERRUPT_OCCURRED: usize = 3;
 pub const NOTIFICATION_NEW_CHANNEL_MESSAGE: usize = 4;
+pub const NOTIFICATION_FAULT: usize = 5;
+pub const NOTIFICATION_CHANNEL_CREDITS_GRANTED: usize = 6;
+pub const NOTIFICATION_CHANNEL_CLOSED: usize = 7;
 
 impl From<Message> for KernelNotification {
     fn from(message: Message) -> Self {
@@ -383,11 +421,23 @@ impl From<Message> for KernelNotification {
                 KernelNotification::ChannelRequest(Tid::new(message.contents[1].try_into().unwrap()))
             }
             NOTIFICATION_CHANNEL_OPENED => KernelNotification::ChannelOpened(ChannelId::new(message.contents[1])),
-            NOTIFICATION_CHANNEL_REQUEST_DENIED => KernelNotification::ChannelRequestDenied,
             NOTIFICATION_INTERRUPT_OCCURRED => KernelNotification::InterruptOccurred(message.contents[1]),
             NOTIFICATION_NEW_CHANNEL_MESSAGE => {
                 KernelNotification::NewChannelMessage(ChannelId::new(message.contents[1]))
             }
+            NOTIFICATION_FAULT => KernelNotification::Fault {
+                addr: message.contents[1],
+                cause: message.contents[2],
+                pc: message.contents[3],
+            },
+            NOTIFICATION_CHANNEL_CREDITS_GRANTED => KernelNotification::ChannelCreditsGranted {
+                channel: ChannelId::new(message.contents[1]),
+                credits: message.contents[2],
+            },
+            NOTIFICATION_CHANNEL_CLOSED => KernelNotification::ChannelClosed {
+                channel: ChannelId::new(message.contents[1]),
+                reason: ExitReason::from_usize(message.contents[2]),
+            },
             _ => unreachable!("bad KernelNotification or used this impl one something that wasn't "),
         }
     }
@@ -406,9 +456,6 @@ impl From<KernelNotification> for Message {
                 contents[0] = NOTIFICATION_CHANNEL_OPENED;
                 contents[1] = id.value();
             }
-            KernelNotification::ChannelRequestDenied => {
-                contents[0] = NOTIFICATION_CHANNEL_REQUEST_DENIED;
-            }
             KernelNotification::InterruptOccurred(n) => {
                 contents[0] = NOTIFICATION_INTERRUPT_OCCURRED;
                 contents[1] = n;
@@ -417,8 +464,48 @@ impl From<KernelNotification> for Message {
                 contents[0] = NOTIFICATION_NEW_CHANNEL_MESSAGE;
                 contents[1] = id.value();
             }
+            KernelNotification::Fault { addr, cause, pc } => {
+                contents[0] = NOTIFICATION_FAULT;
+                contents[1] = addr;
+                contents[2] = cause;
+                contents[3] = pc;
+            }
+            KernelNotification::ChannelCreditsGranted { channel, credits } => {
+                contents[0] = NOTIFICATION_CHANNEL_CREDITS_GRANTED;
+                contents[1] = channel.value();
+                contents[2] = credits;
+            }
+            KernelNotification::ChannelClosed { channel, reason } => {
+                contents[0] = NOTIFICATION_CHANNEL_CLOSED;
+                contents[1] = channel.value();
+                contents[2] = reason.map_or(0, |reason| reason as usize);
+            }
         }
 
         Self { contents }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kernel_sender_is_not_a_task() {
+        let tid = Tid::new(NonZeroUsize::new(1).unwrap());
+
+        assert_eq!(Sender::kernel().as_task(), None);
+        assert!(Sender::kernel() != tid);
+    }
+
+    #[test]
+    fn task_sender_matches_its_tid() {
+        let tid = Tid::new(NonZeroUsize::new(7).unwrap());
+        let other = Tid::new(NonZeroUsize::new(8).unwrap());
+        let sender = Sender::task(tid);
+
+        assert_eq!(sender.as_task(), Some(tid));
+        assert!(sender == tid);
+        assert!(sender != other);
+    }
+}
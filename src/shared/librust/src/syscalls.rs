@@ -7,12 +7,13 @@
 
 pub mod allocation;
 pub mod channel;
+pub mod endpoint;
 pub mod vmspace;
 
 use crate::{
     error::KError,
     message::{KernelNotification, Message, Recipient, Sender, SyscallRequest, SyscallResult},
-    task::Tid,
+    task::{ExitReason, FaultStats, Tid},
 };
 use core::{convert::TryInto, num::NonZeroUsize};
 
@@ -35,6 +36,39 @@ pub enum Syscall {
     CreateVmspace = 13,
     AllocVmspaceObject = 14,
     SpawnVmspace = 15,
+    RegisterFaultHandler = 16,
+    SendChannelRegion = 17,
+    ChannelStats = 18,
+    RetireAndReply = 19,
+    Kill = 20,
+    DumpChannel = 21,
+    Yield = 22,
+    PollReadyChannels = 23,
+    AllowChannelRequests = 24,
+    DisallowChannelRequests = 25,
+    GrantChannelCredits = 26,
+    CancelChannelMessage = 27,
+    GcChannels = 28,
+    CreateEndpoint = 29,
+    SendEndpoint = 30,
+    ReceiveEndpoint = 31,
+    CreateChannelPair = 32,
+    TrimChannelMessage = 33,
+    TlsBase = 34,
+    LendChannelRegion = 35,
+    RecallChannelRegion = 36,
+    SendChannelMessageShared = 37,
+    CurrentHart = 38,
+    OnlineHarts = 39,
+    SetChannelLabel = 40,
+    ChannelLabel = 41,
+    ReceiveAny = 42,
+    SendChannelMessageBlocking = 43,
+    FaultStats = 44,
+    DupChannel = 45,
+    CloseChannel = 46,
+    SpliceChannels = 47,
+    GrantEndpoint = 48,
 }
 
 impl Syscall {
@@ -56,6 +90,39 @@ impl Syscall {
             13 => Some(Self::CreateVmspace),
             14 => Some(Self::AllocVmspaceObject),
             15 => Some(Self::SpawnVmspace),
+            16 => Some(Self::RegisterFaultHandler),
+            17 => Some(Self::SendChannelRegion),
+            18 => Some(Self::ChannelStats),
+            19 => Some(Self::RetireAndReply),
+            20 => Some(Self::Kill),
+            21 => Some(Self::DumpChannel),
+            22 => Some(Self::Yield),
+            23 => Some(Self::PollReadyChannels),
+            24 => Some(Self::AllowChannelRequests),
+            25 => Some(Self::DisallowChannelRequests),
+            26 => Some(Self::GrantChannelCredits),
+            27 => Some(Self::CancelChannelMessage),
+            28 => Some(Self::GcChannels),
+            29 => Some(Self::CreateEndpoint),
+            30 => Some(Self::SendEndpoint),
+            31 => Some(Self::ReceiveEndpoint),
+            32 => Some(Self::CreateChannelPair),
+            33 => Some(Self::TrimChannelMessage),
+            34 => Some(Self::TlsBase),
+            35 => Some(Self::LendChannelRegion),
+            36 => Some(Self::RecallChannelRegion),
+            37 => Some(Self::SendChannelMessageShared),
+            38 => Some(Self::CurrentHart),
+            39 => Some(Self::OnlineHarts),
+            40 => Some(Self::SetChannelLabel),
+            41 => Some(Self::ChannelLabel),
+            42 => Some(Self::ReceiveAny),
+            43 => Some(Self::SendChannelMessageBlocking),
+            44 => Some(Self::FaultStats),
+            45 => Some(Self::DupChannel),
+            46 => Some(Self::CloseChannel),
+            47 => Some(Self::SpliceChannels),
+            48 => Some(Self::GrantEndpoint),
             _ => None,
         }
     }
@@ -118,7 +185,17 @@ pub fn syscall<T: Into<Message>, U: From<Message>, E: From<Message>>(
 
 #[inline(always)]
 pub fn exit() -> ! {
-    let _ = syscall::<_, (), ()>(Recipient::kernel(), SyscallRequest { syscall: Syscall::Exit, arguments: [0; 12] });
+    exit_with(ExitReason::Exited)
+}
+
+/// Exit, telling the other end of any channels the task has open why. Only
+/// [`ExitReason::Exited`] and [`ExitReason::Panicked`] can be chosen by the
+/// task itself, anything else is reported as a normal exit.
+#[inline(always)]
+pub fn exit_with(reason: ExitReason) -> ! {
+    let mut arguments = [0; 12];
+    arguments[0] = reason as usize;
+    let _ = syscall::<_, (), ()>(Recipient::kernel(), SyscallRequest { syscall: Syscall::Exit, arguments });
 
     unreachable!()
 }
@@ -174,6 +251,91 @@ pub fn send_message(tid: Tid, message: Message) -> SyscallResult<(), KError> {
     syscall(Recipient::task(tid), message).1
 }
 
+/// The start of the thread-local storage region the kernel set up for the
+/// current task, if it has one
+#[inline]
+pub fn tls_base() -> Option<*mut u8> {
+    let (_, result) = syscall::<_, usize, KError>(
+        Recipient::kernel(),
+        SyscallRequest { syscall: Syscall::TlsBase, arguments: [0; 12] },
+    );
+
+    match result {
+        SyscallResult::Ok(0) | SyscallResult::Err(_) => None,
+        SyscallResult::Ok(base) => Some(base as *mut u8),
+    }
+}
+
+/// The ID of the hart the current task is running on. Tasks can be moved
+/// between harts whenever they're preempted, so this is only a hint by the
+/// time it's returned.
+#[inline]
+pub fn current_hart() -> usize {
+    let (_, result) = syscall::<_, usize, KError>(
+        Recipient::kernel(),
+        SyscallRequest { syscall: Syscall::CurrentHart, arguments: [0; 12] },
+    );
+
+    result.unwrap()
+}
+
+/// The number of harts which were brought online at boot and can run tasks
+#[inline]
+pub fn online_harts() -> usize {
+    let (_, result) = syscall::<_, usize, KError>(
+        Recipient::kernel(),
+        SyscallRequest { syscall: Syscall::OnlineHarts, arguments: [0; 12] },
+    );
+
+    result.unwrap()
+}
+
+/// The number of page faults the current task has taken so far
+#[inline]
+pub fn fault_stats() -> FaultStats {
+    let (_, result) = syscall::<_, FaultStats, KError>(
+        Recipient::kernel(),
+        SyscallRequest { syscall: Syscall::FaultStats, arguments: [0; 12] },
+    );
+
+    result.unwrap()
+}
+
+/// Give up the remainder of the current task's timeslice
+#[inline]
+pub fn yield_now() {
+    let _ = syscall::<_, (), ()>(Recipient::kernel(), SyscallRequest { syscall: Syscall::Yield, arguments: [0; 12] });
+}
+
+/// Forcibly terminate the task `tid`, which must have been spawned by the
+/// current task. Killing the current task is equivalent to [`exit`].
+#[inline]
+pub fn kill(tid: Tid) -> SyscallResult<(), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest { syscall: Syscall::Kill, arguments: [tid.value(), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] },
+    )
+    .1
+}
+
+/// Register a function to be jumped to when the current task faults instead of
+/// being killed. The handler is called with the faulting address, the trap
+/// cause, and the address of the faulting instruction, and the same is placed
+/// at the front of the message queue as a [`KernelNotification::Fault`] for an
+/// event loop the handler hands off to. Faulting again before re-registering
+/// the handler will kill the task.
+#[inline]
+pub fn register_fault_handler(handler: extern "C" fn(usize, usize, usize) -> !) -> SyscallResult<(), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::RegisterFaultHandler,
+            arguments: [handler as usize, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
 #[inline]
 pub fn current_tid() -> Tid {
     Tid::new(
@@ -7,17 +7,83 @@
 
 use crate::{
     error::KError,
-    message::{Recipient, SyscallRequest, SyscallResult},
+    message::{Message, Recipient, SyscallRequest, SyscallResult},
     syscalls::{syscall, Syscall},
     task::Tid,
 };
+use core::{iter::FromIterator, num::NonZeroUsize};
 
 #[derive(Debug, Clone, Copy)]
 #[repr(C)]
 pub struct ChannelMessage {
+    /// Identifies the message when retiring or replying to it. IDs are
+    /// allocated when a message is created and so don't reflect the order in
+    /// which messages were sent, use `sequence` for that instead.
     pub id: MessageId,
     pub ptr: *mut u8,
+    /// The length of the message payload
     pub len: usize,
+    /// The total size of the memory region backing the message, which is
+    /// always at least `len` bytes
+    pub capacity: usize,
+    /// Whether the message region may be written to
+    pub access: MessageAccess,
+    /// The message this is a reply to, as given to [`send_reply`]
+    pub reply_to: Option<MessageId>,
+    /// For received messages, the number of messages the sender had sent on
+    /// the channel including this one. Sequence numbers increase by exactly
+    /// one per message, so a gap means a message was never received. Zero
+    /// for messages that haven't been sent.
+    pub sequence: usize,
+    /// The tag the sender attached with [`send_tagged_message`], or zero
+    pub tag: u64,
+    /// For received messages, the task which sent the message. This is always
+    /// the task on the other end of the channel it arrived on, unless the
+    /// channel has been spliced since it was sent. `None` for messages that
+    /// haven't been sent.
+    pub sender: Option<Tid>,
+}
+
+impl ChannelMessage {
+    /// Rebuild a received message from the registers the kernel returned it in
+    fn from_parts(parts: (usize, usize, usize, usize, usize, usize, usize, usize, usize)) -> Self {
+        let (id, ptr, len, capacity, access, reply_to, sequence, tag, sender) = parts;
+        Self {
+            id: MessageId::new(id),
+            ptr: ptr as *mut u8,
+            len,
+            capacity,
+            access: MessageAccess::from_usize(access).unwrap(),
+            reply_to: match reply_to {
+                0 => None,
+                id => Some(MessageId::new(id)),
+            },
+            sequence,
+            tag: tag as u64,
+            sender: NonZeroUsize::new(sender).map(Tid::new),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum MessageAccess {
+    ReadOnly = 0,
+    ReadWrite = 1,
+}
+
+impl MessageAccess {
+    pub fn from_usize(n: usize) -> Option<Self> {
+        match n {
+            0 => Some(Self::ReadOnly),
+            1 => Some(Self::ReadWrite),
+            _ => None,
+        }
+    }
+
+    pub fn is_writable(self) -> bool {
+        matches!(self, Self::ReadWrite)
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -48,6 +114,109 @@ impl MessageId {
     }
 }
 
+/// Traffic counters for one end of a channel
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelStats {
+    /// Number of messages created with [`create_message`]
+    pub created: usize,
+    /// Number of messages sent to the other end of the channel
+    pub sent: usize,
+    /// Number of messages returned by [`read_message`]
+    pub read: usize,
+    /// Number of received messages retired with [`retire_message`]
+    pub retired: usize,
+    /// Total number of payload bytes sent to the other end of the channel
+    pub bytes_sent: usize,
+    /// Messages which have been created but not yet sent
+    pub pending_writes: usize,
+    /// Messages which have been received but not yet retired
+    pub pending_reads: usize,
+    /// The most messages there have been received but not yet retired at once
+    pub peak_pending_reads: usize,
+}
+
+impl From<ChannelStats> for Message {
+    fn from(stats: ChannelStats) -> Self {
+        Message::from((
+            stats.created,
+            stats.sent,
+            stats.read,
+            stats.retired,
+            stats.bytes_sent,
+            stats.pending_writes,
+            stats.pending_reads,
+            stats.peak_pending_reads,
+        ))
+    }
+}
+
+/// The maximum size of the message which can be sent along with opening a
+/// channel with [`create_channel_with_initial_message`]
+pub const MAX_INITIAL_MESSAGE_BYTES: usize = 256;
+
+/// The maximum number of channel IDs returned by a single call to
+/// [`poll_ready_channels`]
+pub const MAX_READY_CHANNELS: usize = 12;
+
+/// Channels which have at least one received message waiting to be read
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReadyChannels {
+    count: usize,
+    ids: [usize; MAX_READY_CHANNELS],
+}
+
+impl ReadyChannels {
+    pub fn iter(&self) -> impl Iterator<Item = ChannelId> + '_ {
+        self.ids[..self.count].iter().copied().map(ChannelId)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+}
+
+impl FromIterator<ChannelId> for ReadyChannels {
+    fn from_iter<I: IntoIterator<Item = ChannelId>>(iter: I) -> Self {
+        let mut ready = Self::default();
+        for (slot, id) in ready.ids.iter_mut().zip(iter) {
+            *slot = id.value();
+            ready.count += 1;
+        }
+
+        ready
+    }
+}
+
+impl From<ReadyChannels> for Message {
+    fn from(ready: ReadyChannels) -> Self {
+        let mut contents = [0; 13];
+        contents[0] = ready.count;
+        contents[1..].copy_from_slice(&ready.ids);
+
+        Message { contents }
+    }
+}
+
+impl From<Message> for ReadyChannels {
+    fn from(msg: Message) -> Self {
+        let mut ids = [0; MAX_READY_CHANNELS];
+        ids.copy_from_slice(&msg.contents[1..]);
+
+        Self { count: msg.contents[0].min(MAX_READY_CHANNELS), ids }
+    }
+}
+
+impl From<Message> for ChannelStats {
+    fn from(msg: Message) -> Self {
+        let (created, sent, read, retired, bytes_sent, pending_writes, pending_reads, peak_pending_reads) = msg.into();
+        Self { created, sent, read, retired, bytes_sent, pending_writes, pending_reads, peak_pending_reads }
+    }
+}
+
+/// Ask `with` to open a channel with the current task, blocking until it
+/// does. Fails with [`KError::InvalidRecipient`] if there's no such task,
+/// [`KError::RecipientDead`] if it's died, or [`KError::ConnectionRefused`] if
+/// it isn't accepting requests from the current task.
 pub fn request_channel(with: Tid) -> SyscallResult<(), KError> {
     syscall(
         Recipient::kernel(),
@@ -56,15 +225,189 @@ pub fn request_channel(with: Tid) -> SyscallResult<(), KError> {
     .1
 }
 
+/// Accept channel requests from `from` even when the current task isn't
+/// accepting requests from everyone
+pub fn allow_channel_requests(from: Tid) -> SyscallResult<(), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::AllowChannelRequests,
+            arguments: [from.value(), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
+/// Undo a previous [`allow_channel_requests`]
+pub fn disallow_channel_requests(from: Tid) -> SyscallResult<(), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::DisallowChannelRequests,
+            arguments: [from.value(), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
 pub fn create_channel(with: Tid) -> SyscallResult<ChannelId, KError> {
+    create_channel_with_options(with, ChannelOptions::default())
+}
+
+/// Configuration for a new channel, applying to both ends of the channel
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelOptions {
+    /// The maximum size of a single message, or the kernel default if `None`.
+    /// The kernel enforces this on both ends of the channel, so creating a
+    /// larger message or sending a larger region fails with
+    /// [`crate::error::ChannelArgumentError::MessageTooLarge`].
+    pub max_message_size: Option<usize>,
+    /// Enables flow control: each end of the channel starts with this many
+    /// credits, and sending a message fails with [`KError::ChannelFull`] once
+    /// they've run out. A credit is returned to the sender each time the
+    /// receiver retires a message, or more can be given with
+    /// [`grant_channel_credits`].
+    pub credits: Option<usize>,
+}
+
+/// Same as [`create_channel`], but with non-default [`ChannelOptions`]
+pub fn create_channel_with_options(with: Tid, options: ChannelOptions) -> SyscallResult<ChannelId, KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::CreateChannel,
+            arguments: [
+                with.value(),
+                options.max_message_size.unwrap_or(0),
+                options.credits.unwrap_or(0),
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+            ],
+        },
+    )
+    .1
+    .map(ChannelId)
+}
+
+/// Same as [`create_channel_with_options`], but `message` is delivered to `with`
+/// as the first message on the channel, before anything that's sent in
+/// response to it opening. `message` can be at most
+/// [`MAX_INITIAL_MESSAGE_BYTES`] long, which saves a round trip for handing
+/// over something small like a startup descriptor.
+pub fn create_channel_with_initial_message(
+    with: Tid,
+    options: ChannelOptions,
+    message: &[u8],
+) -> SyscallResult<ChannelId, KError> {
     syscall(
         Recipient::kernel(),
-        SyscallRequest { syscall: Syscall::CreateChannel, arguments: [with.value(), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] },
+        SyscallRequest {
+            syscall: Syscall::CreateChannel,
+            arguments: [
+                with.value(),
+                options.max_message_size.unwrap_or(0),
+                options.credits.unwrap_or(0),
+                message.as_ptr() as usize,
+                message.len(),
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+            ],
+        },
     )
     .1
     .map(ChannelId)
 }
 
+/// Accept the channel request which has been pending the longest, returning
+/// the new channel and the task that requested it. Servers which accept this
+/// way instead of picking a [`Tid`] admit requesters fairly, in the order they
+/// asked. Fails with [`KError::InvalidOperation`] if there are no pending
+/// requests.
+pub fn accept_oldest_channel_request(options: ChannelOptions) -> SyscallResult<(ChannelId, Tid), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::CreateChannel,
+            arguments: [
+                0,
+                options.max_message_size.unwrap_or(0),
+                options.credits.unwrap_or(0),
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+            ],
+        },
+    )
+    .1
+    .map(|(id, tid): (usize, usize)| (ChannelId(id), Tid::new(NonZeroUsize::new(tid).unwrap())))
+}
+
+/// Create a channel directly between two tasks spawned by the current task,
+/// similar to `socketpair`. Neither task has to request or accept the channel,
+/// and each is sent a [`crate::message::KernelNotification::ChannelOpened`]
+/// for its end. The returned IDs are the ends belonging to `task_a` and
+/// `task_b` respectively.
+pub fn create_channel_pair(
+    task_a: Tid,
+    task_b: Tid,
+    options: ChannelOptions,
+) -> SyscallResult<(ChannelId, ChannelId), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::CreateChannelPair,
+            arguments: [
+                task_a.value(),
+                task_b.value(),
+                options.max_message_size.unwrap_or(0),
+                options.credits.unwrap_or(0),
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+                0,
+            ],
+        },
+    )
+    .1
+    .map(|(a, b)| (ChannelId(a), ChannelId(b)))
+}
+
+/// Give the other end of a flow controlled channel `credits` more messages
+/// that it can send, which it's notified of with
+/// [`crate::message::KernelNotification::ChannelCreditsGranted`]
+pub fn grant_channel_credits(channel: ChannelId, credits: usize) -> SyscallResult<(), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::GrantChannelCredits,
+            arguments: [channel.value(), credits, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
 pub fn create_message(channel: ChannelId, size: usize) -> SyscallResult<ChannelMessage, KError> {
     syscall(
         Recipient::kernel(),
@@ -74,7 +417,17 @@ pub fn create_message(channel: ChannelId, size: usize) -> SyscallResult<ChannelM
         },
     )
     .1
-    .map(|(id, ptr, len)| ChannelMessage { id: MessageId::new(id), ptr: ptr as *mut u8, len })
+    .map(|(id, ptr, len, capacity)| ChannelMessage {
+        id: MessageId::new(id),
+        ptr: ptr as *mut u8,
+        len,
+        capacity,
+        access: MessageAccess::ReadWrite,
+        reply_to: None,
+        sequence: 0,
+        tag: 0,
+        sender: None,
+    })
 }
 
 pub fn send_message(channel: ChannelId, message: MessageId, message_len: usize) -> SyscallResult<(), KError> {
@@ -88,6 +441,148 @@ pub fn send_message(channel: ChannelId, message: MessageId, message_len: usize)
     .1
 }
 
+/// Same as [`send_message`], but attaches `tag` to the message, which the
+/// receiver gets back unchanged in [`ChannelMessage::tag`]. Useful for things
+/// like opcodes, which can then be dispatched on without parsing the message.
+pub fn send_tagged_message(
+    channel: ChannelId,
+    message: MessageId,
+    message_len: usize,
+    tag: u64,
+) -> SyscallResult<(), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::SendChannelMessage,
+            arguments: [channel.value(), message.value(), message_len, 0, tag as usize, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
+/// Same as [`send_message`], but marks the message as a reply to the received
+/// message `reply_to`, which the receiver gets back in
+/// [`ChannelMessage::reply_to`] to match up replies with in-flight requests
+pub fn send_reply(
+    channel: ChannelId,
+    message: MessageId,
+    message_len: usize,
+    reply_to: MessageId,
+) -> SyscallResult<(), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::SendChannelMessage,
+            arguments: [channel.value(), message.value(), message_len, reply_to.value(), 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
+/// Same as [`send_message`], but instead of returning [`KError::ChannelFull`]
+/// when the channel is out of credits, blocks until the receiver retires a
+/// message and then sends it. Returns [`KError::ChannelClosed`] if the other
+/// end of the channel closes first.
+pub fn send_message_blocking(channel: ChannelId, message: MessageId, message_len: usize) -> SyscallResult<(), KError> {
+    loop {
+        let res = syscall(
+            Recipient::kernel(),
+            SyscallRequest {
+                syscall: Syscall::SendChannelMessageBlocking,
+                arguments: [channel.value(), message.value(), message_len, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            },
+        )
+        .1;
+
+        match res {
+            // The kernel blocks the task until a credit is returned or the
+            // channel closes before this is returned
+            SyscallResult::Err(KError::ChannelFull) => continue,
+            res => return res,
+        }
+    }
+}
+
+/// The maximum number of extra channels a message can be sent on with
+/// [`send_message_shared`]
+pub const MAX_SHARED_MESSAGE_CHANNELS: usize = 8;
+
+/// Same as [`send_message`], but the message is also sent to the other end of
+/// each channel in `also_to` without being copied. Every receiver gets the
+/// message read-only, and the memory backing it is freed once they've all
+/// retired it. Receivers on the `also_to` channels see the message with an ID
+/// from their channel rather than `message`.
+pub fn send_message_shared(
+    channel: ChannelId,
+    message: MessageId,
+    message_len: usize,
+    also_to: &[ChannelId],
+) -> SyscallResult<(), KError> {
+    if also_to.len() > MAX_SHARED_MESSAGE_CHANNELS {
+        return SyscallResult::Err(KError::InvalidArgument(3));
+    }
+
+    let mut arguments = [0; 12];
+    arguments[0] = channel.value();
+    arguments[1] = message.value();
+    arguments[2] = message_len;
+    arguments[3] = also_to.len();
+    for (argument, id) in arguments[4..].iter_mut().zip(also_to) {
+        *argument = id.value();
+    }
+
+    syscall(Recipient::kernel(), SyscallRequest { syscall: Syscall::SendChannelMessageShared, arguments }).1
+}
+
+/// Transfer ownership of a memory region previously allocated by the current
+/// task (e.g. with [`crate::syscalls::allocation::alloc_virtual_memory`]) to
+/// the other side of the channel, unmapping it from the current task. Returns
+/// the [`MessageId`] the region will be received as.
+pub fn send_region(channel: ChannelId, region: *mut u8, len: usize) -> SyscallResult<MessageId, KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::SendChannelRegion,
+            arguments: [channel.value(), region as usize, len, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+    .map(MessageId)
+}
+
+/// Same as [`send_region`], but the region can later be taken back with
+/// [`recall_region`] using the returned [`MessageId`]. The receiver can't send
+/// the region on to anyone else.
+pub fn lend_region(channel: ChannelId, region: *mut u8, len: usize) -> SyscallResult<MessageId, KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::LendChannelRegion,
+            arguments: [channel.value(), region as usize, len, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+    .map(MessageId)
+}
+
+/// Take back a region lent with [`lend_region`], mapping it back into the
+/// current task and returning its new address. The region is unmapped from
+/// the receiver, which faults the same as for any other unmapped memory if it
+/// accesses the region afterwards.
+pub fn recall_region(channel: ChannelId, message: MessageId) -> SyscallResult<*mut u8, KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::RecallChannelRegion,
+            arguments: [channel.value(), message.value(), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
+/// Read the next message received on the channel, or `None` if there isn't one
+/// yet. Once the other end of the channel has closed and every message it sent
+/// has been read, this returns [`KError::ChannelClosed`] instead.
 pub fn read_message(channel: ChannelId) -> SyscallResult<Option<ChannelMessage>, KError> {
     syscall(
         Recipient::kernel(),
@@ -95,11 +590,70 @@ pub fn read_message(channel: ChannelId) -> SyscallResult<Option<ChannelMessage>,
     )
     .1
     .map(|res| match res {
-        (0, 0, 0) => None,
-        (id, ptr, len) => Some(ChannelMessage { id: MessageId::new(id), ptr: ptr as *mut u8, len }),
+        (0, 0, 0, 0, 0, 0, 0, 0, 0) => None,
+        parts => Some(ChannelMessage::from_parts(parts)),
     })
 }
 
+/// Read the first message received on any of the current task's channels,
+/// along with the channel it arrived on, blocking until one arrives if none of
+/// them have a message waiting. A channel is returned without a message once
+/// the other end has closed and every message it sent has been read, and keeps
+/// being returned until it's closed or reclaimed with [`gc_channels`].
+pub fn receive_any() -> SyscallResult<(ChannelId, Option<ChannelMessage>), KError> {
+    loop {
+        let res = syscall(Recipient::kernel(), SyscallRequest { syscall: Syscall::ReceiveAny, arguments: [0; 12] }).1;
+
+        match res {
+            // The kernel blocks the task until a message arrives before this
+            // is returned, so there'll be one waiting when trying again
+            SyscallResult::Err(KError::NoMessages) => continue,
+            res => {
+                return res.map(|(channel, id, ptr, len, capacity, access, reply_to, sequence, tag, sender)| {
+                    let parts = (id, ptr, len, capacity, access, reply_to, sequence, tag, sender);
+                    let message = match id {
+                        0 => None,
+                        _ => Some(ChannelMessage::from_parts(parts)),
+                    };
+
+                    (ChannelId::new(channel), message)
+                })
+            }
+        }
+    }
+}
+
+/// Shrink a message created with [`create_message`] which hasn't been sent yet
+/// to the pages needed for `new_size` bytes, freeing the rest of its memory.
+/// Returns the new capacity of the message, which stays at the same address.
+pub fn trim_message(channel: ChannelId, message: MessageId, new_size: usize) -> SyscallResult<usize, KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::TrimChannelMessage,
+            arguments: [channel.value(), message.value(), new_size, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
+/// Free a message created with [`create_message`] that won't be sent after
+/// all. Received messages are freed with [`retire_message`] instead, which
+/// doesn't require them to have been read first.
+pub fn cancel_message(channel: ChannelId, message: MessageId) -> SyscallResult<(), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::CancelChannelMessage,
+            arguments: [channel.value(), message.value(), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
+/// Unmap and free the entire region backing a received message. The region
+/// stays mapped from when the message is received until it's retired, and
+/// there's no way to retire only part of it.
 pub fn retire_message(channel: ChannelId, message: MessageId) -> SyscallResult<(), KError> {
     syscall(
         Recipient::kernel(),
@@ -110,3 +664,153 @@ pub fn retire_message(channel: ChannelId, message: MessageId) -> SyscallResult<(
     )
     .1
 }
+
+/// Retire the received message `message` and allocate a new message of
+/// `reply_size` bytes in a single syscall. The reply isn't sent until
+/// [`send_message`] is called on it, giving the caller a chance to write the
+/// reply contents first. If the reply can't be allocated, `message` is left
+/// unretired.
+pub fn retire_and_reply(
+    channel: ChannelId,
+    message: MessageId,
+    reply_size: usize,
+) -> SyscallResult<ChannelMessage, KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::RetireAndReply,
+            arguments: [channel.value(), message.value(), reply_size, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+    .map(|(id, ptr, len, capacity)| ChannelMessage {
+        id: MessageId::new(id),
+        ptr: ptr as *mut u8,
+        len,
+        capacity,
+        access: MessageAccess::ReadWrite,
+        reply_to: None,
+        sequence: 0,
+        tag: 0,
+        sender: None,
+    })
+}
+
+pub fn channel_stats(channel: ChannelId) -> SyscallResult<ChannelStats, KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::ChannelStats,
+            arguments: [channel.value(), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
+/// The maximum length in bytes of a label set with [`set_channel_label`]
+pub const MAX_CHANNEL_LABEL_LEN: usize = 32;
+
+/// Attach a human-readable label to the task's end of the channel, which the
+/// kernel includes when dumping the channel so it's easier to tell apart from
+/// the task's other channels. `label` can be at most [`MAX_CHANNEL_LABEL_LEN`]
+/// bytes long, and an empty label removes the current one.
+pub fn set_channel_label(channel: ChannelId, label: &str) -> SyscallResult<(), KError> {
+    if label.len() > MAX_CHANNEL_LABEL_LEN {
+        return SyscallResult::Err(KError::InvalidArgument(1));
+    }
+
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::SetChannelLabel,
+            arguments: [channel.value(), label.as_ptr() as usize, label.len(), 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
+/// Copy the label of the channel into `buffer`, returning the label's length,
+/// which is zero if the channel doesn't have one. A buffer of
+/// [`MAX_CHANNEL_LABEL_LEN`] bytes is always large enough to hold the label,
+/// otherwise it's truncated to fit.
+pub fn channel_label(channel: ChannelId, buffer: &mut [u8]) -> SyscallResult<usize, KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::ChannelLabel,
+            arguments: [channel.value(), buffer.as_mut_ptr() as usize, buffer.len(), 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
+/// Ask the kernel to log the full state of the channel, only available when
+/// the kernel is built with debug assertions enabled
+pub fn dump_channel(channel: ChannelId) -> SyscallResult<(), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest { syscall: Syscall::DumpChannel, arguments: [channel.value(), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] },
+    )
+    .1
+}
+
+/// Find which channels have received messages waiting to be read, so that a
+/// task multiplexing many channels doesn't need to poll each of them. At most
+/// [`MAX_READY_CHANNELS`] channels are returned.
+pub fn poll_ready_channels() -> SyscallResult<ReadyChannels, KError> {
+    syscall(Recipient::kernel(), SyscallRequest { syscall: Syscall::PollReadyChannels, arguments: [0; 12] }).1
+}
+
+/// Create another handle to the channel in the current task, which can be used
+/// anywhere the original can. Messages belong to the channel rather than to a
+/// handle, so they can be read, retired, or sent through any of its handles.
+/// The channel stays open until every handle to it has been closed with
+/// [`close_channel`].
+pub fn dup_channel(channel: ChannelId) -> SyscallResult<ChannelId, KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest { syscall: Syscall::DupChannel, arguments: [channel.value(), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0] },
+    )
+    .1
+    .map(ChannelId)
+}
+
+/// Close a handle to a channel. Once the last handle to the channel is closed,
+/// the current task's end of it is closed and any messages still on it are
+/// freed, and the other end of the channel is reclaimed by [`gc_channels`].
+pub fn close_channel(channel: ChannelId) -> SyscallResult<(), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::CloseChannel,
+            arguments: [channel.value(), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
+/// Connect the tasks on the other ends of channels `a` and `b` directly to
+/// each other, closing the current task's ends of both. Any messages received
+/// on either channel which haven't been retired yet are moved over to the task
+/// on the other side. This lets a task which brokered a connection between two
+/// others drop out of the path once it's no longer needed.
+pub fn splice_channels(a: ChannelId, b: ChannelId) -> SyscallResult<(), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::SpliceChannels,
+            arguments: [a.value(), b.value(), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
+/// Reclaim channels whose other end has been closed or whose peer task has
+/// died, freeing any messages still on them. A
+/// [`crate::message::KernelNotification::ChannelClosed`] is delivered for each
+/// channel that's reclaimed, carrying the reason the peer task exited if it
+/// died and that's still known. Returns the number of channels reclaimed, which
+/// is bounded per call, so there may be more left if it's non-zero.
+pub fn gc_channels() -> SyscallResult<usize, KError> {
+    syscall(Recipient::kernel(), SyscallRequest { syscall: Syscall::GcChannels, arguments: [0; 12] }).1
+}
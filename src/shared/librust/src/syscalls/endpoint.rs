@@ -0,0 +1,106 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2021 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use core::num::NonZeroUsize;
+
+use super::{syscall, Syscall};
+use crate::{
+    error::KError,
+    message::{Message, Recipient, SyscallRequest, SyscallResult},
+    task::Tid,
+};
+
+/// The number of words of data carried by a single [`EndpointMessage`]
+pub const ENDPOINT_MESSAGE_WORDS: usize = 10;
+/// The number of messages an endpoint can have waiting to be received before
+/// sending to it fails with [`KError::RecipientFull`]
+pub const MAX_ENDPOINT_MESSAGES: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(transparent)]
+pub struct EndpointId(usize);
+
+impl EndpointId {
+    pub fn new(id: usize) -> Self {
+        Self(id)
+    }
+
+    pub fn value(self) -> usize {
+        self.0
+    }
+}
+
+/// A message sent to an endpoint, along with the task that sent it
+#[derive(Debug, Clone, Copy)]
+pub struct EndpointMessage {
+    pub sender: Tid,
+    pub contents: [usize; ENDPOINT_MESSAGE_WORDS],
+}
+
+impl From<EndpointMessage> for Message {
+    fn from(message: EndpointMessage) -> Self {
+        let mut contents = [0; 13];
+        contents[0] = message.sender.value();
+        contents[1..][..ENDPOINT_MESSAGE_WORDS].copy_from_slice(&message.contents);
+
+        Message { contents }
+    }
+}
+
+/// Create an endpoint owned by the current task, which tasks it's been granted
+/// to with [`grant_endpoint`] can send messages to with [`send_endpoint`].
+/// Unlike channels, all of the messages arrive in one place, which suits
+/// servers with many clients.
+pub fn create_endpoint() -> SyscallResult<EndpointId, KError> {
+    syscall(Recipient::kernel(), SyscallRequest { syscall: Syscall::CreateEndpoint, arguments: [0; 12] })
+        .1
+        .map(EndpointId)
+}
+
+/// Allow `task` to send messages to one of the current task's endpoints
+pub fn grant_endpoint(endpoint: EndpointId, task: Tid) -> SyscallResult<(), KError> {
+    syscall(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::GrantEndpoint,
+            arguments: [endpoint.value(), task.value(), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+}
+
+pub fn send_endpoint(
+    task: Tid,
+    endpoint: EndpointId,
+    contents: [usize; ENDPOINT_MESSAGE_WORDS],
+) -> SyscallResult<(), KError> {
+    let mut arguments = [0; 12];
+    arguments[0] = task.value();
+    arguments[1] = endpoint.value();
+    arguments[2..].copy_from_slice(&contents);
+
+    syscall(Recipient::kernel(), SyscallRequest { syscall: Syscall::SendEndpoint, arguments }).1
+}
+
+/// Take the oldest message sent to one of the current task's endpoints, or
+/// [`KError::NoMessages`] if there aren't any
+pub fn receive_endpoint(endpoint: EndpointId) -> SyscallResult<EndpointMessage, KError> {
+    syscall::<_, Message, KError>(
+        Recipient::kernel(),
+        SyscallRequest {
+            syscall: Syscall::ReceiveEndpoint,
+            arguments: [endpoint.value(), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        },
+    )
+    .1
+    .map(|message| {
+        let mut contents = [0; ENDPOINT_MESSAGE_WORDS];
+        contents.copy_from_slice(&message.contents[1..][..ENDPOINT_MESSAGE_WORDS]);
+
+        EndpointMessage { sender: Tid::new(NonZeroUsize::new(message.contents[0]).unwrap()), contents }
+    })
+}
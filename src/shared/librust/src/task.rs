@@ -5,6 +5,7 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
+use crate::message::Message;
 use core::num::NonZeroUsize;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -23,3 +24,54 @@ impl Tid {
         todo!("get tid")
     }
 }
+
+/// Why a task stopped running
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum ExitReason {
+    /// The task exited normally
+    Exited = 1,
+    /// The task panicked before exiting
+    Panicked = 2,
+    /// The task was killed by its parent
+    Killed = 3,
+    /// The task was killed by the kernel after an unhandled fault
+    Faulted = 4,
+}
+
+impl ExitReason {
+    pub fn from_usize(n: usize) -> Option<Self> {
+        match n {
+            1 => Some(Self::Exited),
+            2 => Some(Self::Panicked),
+            3 => Some(Self::Killed),
+            4 => Some(Self::Faulted),
+            _ => None,
+        }
+    }
+}
+
+/// The number of page faults a task has taken, by how they were handled
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FaultStats {
+    /// Faults the kernel resolved without the task noticing, such as the first
+    /// touch of a lazily allocated page, or an access to a page which needed
+    /// its accessed or dirty bit set
+    pub minor: usize,
+    /// Faults on memory the task isn't allowed to access that way, which are
+    /// delivered to its fault handler if it has one, and kill it otherwise
+    pub protection: usize,
+}
+
+impl From<FaultStats> for Message {
+    fn from(stats: FaultStats) -> Self {
+        Message::from((stats.minor, stats.protection))
+    }
+}
+
+impl From<Message> for FaultStats {
+    fn from(msg: Message) -> Self {
+        let (minor, protection) = msg.into();
+        Self { minor, protection }
+    }
+}
@@ -46,8 +46,7 @@ impl<T, F: Fn() -> T> Lazy<T, F> {
 
     fn wait_for_init(&self) {
         while self.init_state.load(Ordering::Acquire) != 0b10 {
-            // TODO: maybe add ability to specify instruction for stalling?
-            // crate::asm::pause();
+            crate::spin_hint();
         }
     }
 
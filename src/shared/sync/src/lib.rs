@@ -8,17 +8,40 @@
 #![feature(const_fn_trait_bound)]
 #![no_std]
 
+#[cfg(test)]
+extern crate std;
+
 mod lazy;
 mod mutex;
 mod rwlock;
+mod semaphore;
 
 use core::{
     marker::PhantomData,
     sync::atomic::{AtomicPtr, Ordering},
 };
 pub use lazy::Lazy;
-pub use mutex::SpinMutex;
+pub use mutex::{
+    set_hart_id_source, CurrentHart, RawSpinMutex, ReentrantSpinMutex, ReentrantSpinMutexGuard, SpinMutex,
+    SpinMutexGuard, Yield, YieldingSpinMutex,
+};
 pub use rwlock::SpinRwLock;
+pub use semaphore::SpinSemaphore;
+
+/// Hint to the hart that it's spinning while waiting on another hart. With the
+/// Zihintpause extension enabled this emits `pause`, which reduces power usage
+/// and lets the lock holder make progress on SMT implementations.
+#[inline(always)]
+fn spin_hint() {
+    #[cfg(all(target_arch = "riscv64", target_feature = "zihintpause"))]
+    unsafe {
+        // `pause`, encoded by hand since not every assembler knows about it
+        core::arch::asm!(".word 0x0100000F", options(nomem, nostack));
+    }
+
+    #[cfg(not(all(target_arch = "riscv64", target_feature = "zihintpause")))]
+    core::hint::spin_loop();
+}
 
 #[repr(transparent)]
 pub struct AtomicConstPtr<T>(AtomicPtr<T>, PhantomData<T>);
@@ -36,3 +59,21 @@ impl<T> AtomicConstPtr<T> {
         self.0.load(ordering)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn spin_hint_runs() {
+        super::spin_hint();
+    }
+
+    // Only built when testing for a RISC-V target with Zihintpause enabled, to
+    // make sure the hand-encoded `pause` still assembles
+    #[cfg(all(target_arch = "riscv64", target_feature = "zihintpause"))]
+    #[test]
+    fn pause_is_a_fence_hint() {
+        for _ in 0..16 {
+            super::spin_hint();
+        }
+    }
+}
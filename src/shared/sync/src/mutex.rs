@@ -7,17 +7,47 @@
 
 use core::{
     cell::UnsafeCell,
-    sync::atomic::{AtomicBool, Ordering},
+    marker::PhantomData,
+    num::NonZeroUsize,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
 };
 
+/// The function used to find the ID of the current hart, stored as a `usize`
+/// since function pointers can't be stored atomically, or zero if unset
+static HART_ID_SOURCE: AtomicUsize = AtomicUsize::new(0);
+
+/// Set the function used to find the ID of the current hart, which must never
+/// return `usize::MAX` for a real hart. [`ReentrantSpinMutex`] needs this to
+/// tell when it's being re-locked by its owner. In debug builds, [`SpinMutex`]
+/// also uses it to panic when a hart tries to lock a mutex it already holds,
+/// such as from an interrupt handler, instead of silently deadlocking.
+pub fn set_hart_id_source(hart_id: fn() -> usize) {
+    HART_ID_SOURCE.store(hart_id as usize, Ordering::Release);
+}
+
+fn current_hart() -> usize {
+    match HART_ID_SOURCE.load(Ordering::Acquire) {
+        0 => NO_OWNER,
+        source => unsafe { core::mem::transmute::<usize, fn() -> usize>(source)() },
+    }
+}
+
 pub struct SpinMutex<T: Send> {
     lock: AtomicBool,
+    /// The hart holding the lock, only tracked in debug builds
+    #[cfg(debug_assertions)]
+    holder: AtomicUsize,
     data: UnsafeCell<T>,
 }
 
 impl<T: Send> SpinMutex<T> {
     pub const fn new(data: T) -> Self {
-        Self { lock: AtomicBool::new(false), data: UnsafeCell::new(data) }
+        Self {
+            lock: AtomicBool::new(false),
+            #[cfg(debug_assertions)]
+            holder: AtomicUsize::new(NO_OWNER),
+            data: UnsafeCell::new(data),
+        }
     }
 
     pub fn with_lock<U>(&self, f: impl FnOnce(&mut T) -> U) -> U {
@@ -33,16 +63,64 @@ impl<T: Send> SpinMutex<T> {
         SpinMutexGuard { lock: self }
     }
 
+    /// Attempt to acquire the lock once, returning `None` if it's currently
+    /// held. This uses a strong compare-exchange so that `None` really means
+    /// the lock was held, rather than a spurious LR/SC failure.
+    pub fn try_lock(&self) -> Option<SpinMutexGuard<'_, T>> {
+        match self.lock.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed) {
+            Ok(_) => {
+                self.mark_held(true);
+                Some(SpinMutexGuard { lock: self })
+            }
+            Err(_) => None,
+        }
+    }
+
+    /// Attempt to acquire the lock, spinning at most `max_spins` times before
+    /// giving up and returning `None`
+    pub fn try_lock_for(&self, max_spins: usize) -> Option<SpinMutexGuard<'_, T>> {
+        for _ in 0..max_spins {
+            if self.lock.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+                self.mark_held(true);
+                return Some(SpinMutexGuard { lock: self });
+            }
+        }
+
+        None
+    }
+
+    // Spurious failures are harmless when retrying in a loop anyway, and on
+    // RISC-V the weak compare-exchange is a single LR/SC attempt instead of the
+    // retry loop the strong version needs
     fn acquire_lock(&self) {
+        #[cfg(debug_assertions)]
+        {
+            let hart_id = current_hart();
+            if hart_id != NO_OWNER && self.holder.load(Ordering::Relaxed) == hart_id {
+                panic!("hart {} tried to lock a SpinMutex it already holds, which would deadlock", hart_id);
+            }
+        }
+
         while self.lock.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
-            // TODO: maybe add ability to specify instruction for stalling?
-            // crate::asm::pause();
+            crate::spin_hint();
         }
+
+        self.mark_held(true);
     }
 
     fn unlock(&self) {
+        self.mark_held(false);
         self.lock.store(false, Ordering::Release);
     }
+
+    #[inline(always)]
+    fn mark_held(&self, held: bool) {
+        #[cfg(debug_assertions)]
+        self.holder.store(if held { current_hart() } else { NO_OWNER }, Ordering::Relaxed);
+
+        #[cfg(not(debug_assertions))]
+        let _ = held;
+    }
 }
 
 unsafe impl<T: Send> Send for SpinMutex<T> {}
@@ -52,6 +130,21 @@ pub struct SpinMutexGuard<'a, T: Send> {
     lock: &'a SpinMutex<T>,
 }
 
+impl<T: Send> SpinMutexGuard<'_, T> {
+    /// Get something out of the locked data with `f`, then release the lock
+    /// before returning it. Using the result of an expression like
+    /// `mutex.lock().field.take()` in an `if let` or `match` keeps the lock
+    /// held until the end of the whole block, since the guard is a temporary
+    /// that lives as long as the expression it's in, and this makes it easy to
+    /// only hold the lock for as long as it takes to get the value out.
+    ///
+    /// This is an associated function rather than a method so that it can't be
+    /// confused with a method on `T`, e.g. `SpinMutexGuard::extract(guard, f)`.
+    pub fn extract<U>(mut guard: Self, f: impl FnOnce(&mut T) -> U) -> U {
+        f(&mut guard)
+    }
+}
+
 impl<T: Send> core::ops::Deref for SpinMutexGuard<'_, T> {
     type Target = T;
 
@@ -71,3 +164,232 @@ impl<T: Send> Drop for SpinMutexGuard<'_, T> {
         self.lock.unlock()
     }
 }
+
+/// A hook into whatever is scheduling the current thread of execution, so that
+/// the `sync` primitives can give up their timeslice without depending on the
+/// scheduler directly
+pub trait Yield {
+    /// The number of failed attempts to acquire a lock before yielding
+    const SPINS_BEFORE_YIELD: usize = 100;
+
+    /// Give up the remainder of the current timeslice
+    fn yield_now();
+}
+
+/// A [`SpinMutex`] which yields via `Y` after failing to acquire the lock
+/// [`Yield::SPINS_BEFORE_YIELD`] times in a row.
+///
+/// On a single hart, spinning on a lock held by another task can never succeed
+/// since the holder won't be scheduled again until the spinning task gives up
+/// the hart. Yielding lets the holder run and release the lock instead of
+/// livelocking until the next timer interrupt (if there even is one).
+pub struct YieldingSpinMutex<T: Send, Y> {
+    mutex: SpinMutex<T>,
+    _yield: PhantomData<fn() -> Y>,
+}
+
+impl<T: Send, Y: Yield> YieldingSpinMutex<T, Y> {
+    pub const fn new(data: T) -> Self {
+        Self { mutex: SpinMutex::new(data), _yield: PhantomData }
+    }
+
+    pub fn lock(&self) -> SpinMutexGuard<'_, T> {
+        loop {
+            for _ in 0..Y::SPINS_BEFORE_YIELD {
+                if let Some(guard) = self.mutex.try_lock() {
+                    return guard;
+                }
+            }
+
+            Y::yield_now();
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<SpinMutexGuard<'_, T>> {
+        self.mutex.try_lock()
+    }
+}
+
+const NO_OWNER: usize = usize::MAX;
+
+/// A bare spin lock with no data attached, for building other locks with
+/// `lock_api`
+pub struct RawSpinMutex(AtomicBool);
+
+unsafe impl lock_api::RawMutex for RawSpinMutex {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: Self = Self(AtomicBool::new(false));
+    type GuardMarker = lock_api::GuardNoSend;
+
+    fn lock(&self) {
+        while self.0.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            crate::spin_hint();
+        }
+    }
+
+    fn try_lock(&self) -> bool {
+        self.0.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok()
+    }
+
+    unsafe fn unlock(&self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+/// Identifies the current hart for `lock_api` with the function given to
+/// [`set_hart_id_source`]
+pub struct CurrentHart;
+
+unsafe impl lock_api::GetThreadId for CurrentHart {
+    const INIT: Self = CurrentHart;
+
+    fn nonzero_thread_id(&self) -> NonZeroUsize {
+        match current_hart() {
+            NO_OWNER => panic!("a ReentrantSpinMutex was locked before `set_hart_id_source` was called"),
+            // Hart IDs start at zero
+            hart_id => NonZeroUsize::new(hart_id + 1).unwrap(),
+        }
+    }
+}
+
+/// A spin lock which can be locked again by the hart already holding it,
+/// instead of deadlocking, and is only released once every guard on that hart
+/// has been dropped. Since multiple guards to the same data can be alive at
+/// once, they only give out shared references, so mutation has to go through
+/// something like a `Cell` or `RefCell`. Guards can't be sent to other harts.
+///
+/// Ownership is tracked per hart using [`set_hart_id_source`], so this
+/// shouldn't be held across anything that could switch to another task on the
+/// same hart.
+pub type ReentrantSpinMutex<T> = lock_api::ReentrantMutex<RawSpinMutex, CurrentHart, T>;
+pub type ReentrantSpinMutexGuard<'a, T> = lock_api::ReentrantMutexGuard<'a, RawSpinMutex, CurrentHart, T>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::AtomicUsize;
+    use std::sync::Mutex;
+
+    static MUTEX: YieldingSpinMutex<usize, ReleaseOnYield> = YieldingSpinMutex::new(0);
+    static HELD: Mutex<Option<SpinMutexGuard<'static, usize>>> = Mutex::new(None);
+    static YIELDS: AtomicUsize = AtomicUsize::new(0);
+
+    // Models a single hart where the lock holder only gets to run (and
+    // release the lock) once the waiting task yields
+    struct ReleaseOnYield;
+
+    impl Yield for ReleaseOnYield {
+        const SPINS_BEFORE_YIELD: usize = 10;
+
+        fn yield_now() {
+            YIELDS.fetch_add(1, Ordering::Relaxed);
+            HELD.lock().unwrap().take();
+        }
+    }
+
+    #[test]
+    fn yields_to_lock_holder() {
+        *HELD.lock().unwrap() = Some(MUTEX.lock());
+        assert!(MUTEX.try_lock().is_none());
+
+        *MUTEX.lock() += 1;
+
+        assert_eq!(YIELDS.load(Ordering::Relaxed), 1);
+        assert_eq!(*MUTEX.lock(), 1);
+    }
+
+    // Each test thread stands in for a hart, see `TEST_HART_ID`
+    static NEXT_TEST_HART_ID: AtomicUsize = AtomicUsize::new(1);
+
+    fn become_hart() {
+        set_hart_id_source(|| TEST_HART_ID.with(|id| id.get()));
+        TEST_HART_ID.with(|id| id.set(NEXT_TEST_HART_ID.fetch_add(1, Ordering::Relaxed)));
+    }
+
+    #[test]
+    fn reentrant_relock_on_same_hart() {
+        become_hart();
+        let mutex = ReentrantSpinMutex::new(core::cell::Cell::new(0));
+        let locked_elsewhere = || {
+            std::thread::scope(|s| {
+                s.spawn(|| {
+                    become_hart();
+                    mutex.try_lock().is_none()
+                })
+                .join()
+                .unwrap()
+            })
+        };
+
+        let outer = mutex.lock();
+        let inner = mutex.lock();
+        inner.set(inner.get() + 1);
+        assert!(mutex.try_lock().is_some());
+        assert!(locked_elsewhere());
+
+        drop(inner);
+        assert!(locked_elsewhere());
+
+        drop(outer);
+        assert!(!locked_elsewhere());
+        assert_eq!(mutex.lock().get(), 1);
+    }
+
+    #[test]
+    fn try_lock_for_gives_up_after_budget() {
+        let mutex = SpinMutex::new(0);
+        let _guard = mutex.lock();
+
+        assert!(mutex.try_lock_for(1000).is_none());
+    }
+
+    #[test]
+    fn try_lock_for_acquires_when_released() {
+        let mutex = SpinMutex::new(0);
+        let guard = mutex.lock();
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                std::thread::sleep(std::time::Duration::from_millis(10));
+                drop(guard);
+            });
+
+            *mutex.try_lock_for(usize::MAX).unwrap() += 1;
+        });
+
+        assert_eq!(*mutex.lock(), 1);
+        assert!(SpinMutex::new(()).try_lock_for(1).is_some());
+    }
+
+    #[test]
+    fn extract_releases_lock_before_value_is_used() {
+        let mutex = SpinMutex::new(Some(5));
+
+        // With `if let Some(n) = mutex.lock().take()` the lock would still be
+        // held for the whole block
+        if let Some(n) = SpinMutexGuard::extract(mutex.lock(), Option::take) {
+            let mut guard = mutex.try_lock().expect("lock should be free once the value is extracted");
+            *guard = Some(n + 1);
+        }
+
+        assert_eq!(*mutex.lock(), Some(6));
+    }
+
+    std::thread_local! {
+        // Only threads that set this take part in the reentrancy check, so
+        // that tests modelling several tasks sharing a hart aren't affected
+        static TEST_HART_ID: core::cell::Cell<usize> = const { core::cell::Cell::new(NO_OWNER) };
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "tried to lock a SpinMutex it already holds")]
+    fn relock_on_same_hart_panics() {
+        set_hart_id_source(|| TEST_HART_ID.with(|id| id.get()));
+        TEST_HART_ID.with(|id| id.set(0));
+
+        let mutex = SpinMutex::new(0);
+        let _guard = mutex.lock();
+        let _ = mutex.lock();
+    }
+}
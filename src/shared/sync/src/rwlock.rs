@@ -33,8 +33,7 @@ impl<T: Send> SpinRwLock<T> {
 
     fn lock_shared(&self) {
         while !self.try_lock_shared() {
-            // TODO: maybe add ability to specify instruction for stalling?
-            // crate::asm::pause();
+            crate::spin_hint();
         }
     }
 
@@ -66,8 +65,7 @@ impl<T: Send> SpinRwLock<T> {
 
     fn lock_exclusive(&self) {
         while !self.try_lock_exclusive() {
-            // TODO: maybe add ability to specify instruction for stalling?
-            // crate::asm::pause();
+            crate::spin_hint();
         }
     }
 
@@ -135,3 +133,31 @@ impl<T: Send> Drop for ReadGuard<'_, T> {
         self.lock.unlock_shared();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Barrier;
+
+    // Stands in for two harts looking up different tasks in `TASKS` at once
+    #[test]
+    fn readers_dont_block_each_other() {
+        let lock = SpinRwLock::new([1, 2]);
+        let barrier = Barrier::new(2);
+
+        std::thread::scope(|s| {
+            for i in 0..2 {
+                let (lock, barrier) = (&lock, &barrier);
+                s.spawn(move || {
+                    let guard = lock.read();
+                    // Neither reader gets past here unless both hold the lock
+                    barrier.wait();
+                    assert_eq!(guard[i], i + 1);
+                });
+            }
+        });
+
+        lock.write()[0] = 3;
+        assert_eq!(*lock.read(), [3, 2]);
+    }
+}
@@ -0,0 +1,109 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2021 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::Yield;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// A counting semaphore for bounding how many users a resource can have at
+/// once, such as a pool of `N` buffers. Permits aren't tied to whoever acquired
+/// them, so they can be released from anywhere.
+pub struct SpinSemaphore {
+    permits: AtomicUsize,
+}
+
+impl SpinSemaphore {
+    pub const fn new(permits: usize) -> Self {
+        Self { permits: AtomicUsize::new(permits) }
+    }
+
+    /// Take a permit, spinning until one is available
+    pub fn acquire(&self) {
+        while !self.try_acquire() {
+            crate::spin_hint();
+        }
+    }
+
+    /// Same as [`Self::acquire`], but yields via `Y` after failing to take a
+    /// permit [`Yield::SPINS_BEFORE_YIELD`] times in a row, so that a holder on
+    /// the same hart gets the chance to release its permit
+    pub fn acquire_yielding<Y: Yield>(&self) {
+        loop {
+            for _ in 0..Y::SPINS_BEFORE_YIELD {
+                if self.try_acquire() {
+                    return;
+                }
+            }
+
+            Y::yield_now();
+        }
+    }
+
+    /// Attempt to take a permit once, returning whether one was available
+    pub fn try_acquire(&self) -> bool {
+        let mut permits = self.permits.load(Ordering::Relaxed);
+
+        while permits != 0 {
+            match self.permits.compare_exchange_weak(permits, permits - 1, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => return true,
+                Err(current) => permits = current,
+            }
+        }
+
+        false
+    }
+
+    /// Return a permit, allowing another acquirer to proceed
+    pub fn release(&self) {
+        self.permits.fetch_add(1, Ordering::Release);
+    }
+
+    /// The number of permits which are currently available, which may have
+    /// changed by the time it's returned
+    pub fn available(&self) -> usize {
+        self.permits.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn holders_never_exceed_permits() {
+        let semaphore = SpinSemaphore::new(3);
+        let holders = AtomicUsize::new(0);
+        let most_holders = AtomicUsize::new(0);
+
+        std::thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..100 {
+                        semaphore.acquire();
+                        let now = holders.fetch_add(1, Ordering::SeqCst) + 1;
+                        most_holders.fetch_max(now, Ordering::SeqCst);
+                        std::thread::yield_now();
+                        holders.fetch_sub(1, Ordering::SeqCst);
+                        semaphore.release();
+                    }
+                });
+            }
+        });
+
+        assert!(most_holders.load(Ordering::SeqCst) <= 3);
+        assert_eq!(semaphore.available(), 3);
+    }
+
+    #[test]
+    fn try_acquire_fails_when_exhausted() {
+        let semaphore = SpinSemaphore::new(1);
+
+        assert!(semaphore.try_acquire());
+        assert!(!semaphore.try_acquire());
+        semaphore.release();
+        assert!(semaphore.try_acquire());
+    }
+}
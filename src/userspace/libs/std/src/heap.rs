@@ -139,5 +139,6 @@ unsafe impl GlobalAlloc for GlobalTaskLocalAllocator {
     }
 }
 
+#[cfg(not(test))]
 #[global_allocator]
 static TASK_LOCAL: GlobalTaskLocalAllocator = GlobalTaskLocalAllocator;
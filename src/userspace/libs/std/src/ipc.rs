@@ -5,16 +5,26 @@
 // v. 2.0. If a copy of the MPL was not distributed with this file, You can
 // obtain one at https://mozilla.org/MPL/2.0/.
 
+#[cfg(test)]
+mod mock;
+
 use librust::{
+    error::KError,
     message::{KernelNotification, SyscallResult},
     syscalls::{
         self,
-        channel::{self, ChannelId, ChannelMessage},
+        channel::{ChannelId, ChannelMessage, MessageId},
         ReadMessage,
     },
     task::Tid,
 };
 
+// Tests swap the channel syscalls out for an in-memory version of them
+#[cfg(not(test))]
+use librust::syscalls::channel;
+#[cfg(test)]
+use mock as channel;
+
 #[derive(Debug)]
 pub struct IpcChannel {
     id: ChannelId,
@@ -26,12 +36,14 @@ impl IpcChannel {
     }
 
     pub fn open(with: Tid) -> Result<Self, OpenChannelError> {
-        if let SyscallResult::Err(_) = channel::request_channel(with) {
-            return Err(OpenChannelError::InvalidTask);
+        match channel::request_channel(with) {
+            SyscallResult::Ok(()) => {}
+            SyscallResult::Err(KError::ConnectionRefused) => return Err(OpenChannelError::Rejected),
+            SyscallResult::Err(KError::RecipientDead) => return Err(OpenChannelError::TaskDead),
+            SyscallResult::Err(_) => return Err(OpenChannelError::InvalidTask),
         }
 
         match syscalls::receive_message() {
-            Some(ReadMessage::Kernel(KernelNotification::ChannelRequestDenied)) => Err(OpenChannelError::Rejected),
             Some(ReadMessage::Kernel(KernelNotification::ChannelOpened(id))) => Ok(Self { id }),
             t => unreachable!("{:?}", t),
         }
@@ -48,28 +60,81 @@ impl IpcChannel {
         Ok(NewMessage { channel: self, message, cursor: 0 })
     }
 
-    // FIXME: use a real error
-    #[allow(clippy::result_unit_err)]
-    pub fn read(&self) -> Result<Option<Message>, ()> {
+    pub fn read(&self) -> Result<Option<Message>, ReadMessageError> {
         match channel::read_message(self.id) {
             SyscallResult::Ok(maybe_msg) => Ok(maybe_msg.map(|m| Message(self.id, m))),
-            SyscallResult::Err(_) => Err(()),
+            SyscallResult::Err(KError::ChannelClosed) => Err(ReadMessageError::ChannelClosed),
+            SyscallResult::Err(e) => Err(ReadMessageError::Kernel(e)),
+        }
+    }
+
+    /// Receive a message from the channel, blocking until one arrives. If the
+    /// other end of the channel closes or its task dies while waiting, this
+    /// returns [`ReadMessageError::ChannelClosed`] once every message it sent
+    /// has been read.
+    pub fn recv_blocking(&self) -> Result<Message, ReadMessageError> {
+        loop {
+            if let Some(message) = self.read()? {
+                return Ok(message);
+            }
+
+            // The kernel only blocks until something arrives on any of the
+            // task's channels, and leaves whatever that was to be read from
+            // its own channel, so check this one again after waking up
+            match channel::receive_any() {
+                SyscallResult::Ok(_) => {}
+                SyscallResult::Err(e) => return Err(ReadMessageError::Kernel(e)),
+            }
         }
     }
 
+    /// Iterate over the messages arriving on the channel, with `mode`
+    /// deciding what happens once there are none left to read. Each message
+    /// is retired when the yielded [`Message`] is dropped, and messages which
+    /// haven't been yielded yet are left on the channel if the iterator is
+    /// dropped early.
+    pub fn incoming(&self, mode: IncomingMode) -> Incoming<'_> {
+        Incoming { channel: self, mode, finished: false }
+    }
+
     fn send(&mut self, msg: ChannelMessage, written_len: usize) -> Result<(), SendMessageError> {
-        let _ = channel::send_message(self.id, msg.id, written_len);
-        // FIXME: check for failure
-        Ok(())
+        match channel::send_message(self.id, msg.id, written_len) {
+            SyscallResult::Ok(()) => Ok(()),
+            SyscallResult::Err(e) => Err(e.into()),
+        }
     }
 }
 
+/// A message received over a channel. The whole region backing the message
+/// stays mapped until the `Message` is dropped, at which point it's retired in
+/// one go, since messages can't be partially retired. Slices borrowed from the
+/// message are therefore valid for as long as the `Message` itself.
 pub struct Message(ChannelId, ChannelMessage);
 
 impl Message {
+    pub fn id(&self) -> MessageId {
+        self.1.id
+    }
+
+    /// The ID of the message this message is replying to, if any
+    pub fn reply_to(&self) -> Option<MessageId> {
+        self.1.reply_to
+    }
+
+    /// The tag the message was sent with, or zero if it wasn't given one
+    pub fn tag(&self) -> u64 {
+        self.1.tag
+    }
+
     pub fn as_bytes(&self) -> &[u8] {
         unsafe { core::slice::from_raw_parts(self.1.ptr, self.1.len) }
     }
+
+    /// Borrow `len` bytes of the message starting at `offset` without copying
+    /// them, or `None` if the range is outside of the message
+    pub fn subslice(&self, offset: usize, len: usize) -> Option<&[u8]> {
+        self.as_bytes().get(offset..offset.checked_add(len)?)
+    }
 }
 
 impl core::ops::Drop for Message {
@@ -78,6 +143,53 @@ impl core::ops::Drop for Message {
     }
 }
 
+/// What [`Incoming`] does when the channel has no more messages to read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IncomingMode {
+    /// End the iteration
+    NonBlocking,
+    /// Wait until the next message arrives, as with [`IpcChannel::recv_blocking`]
+    Blocking,
+}
+
+/// An iterator over the messages arriving on a channel, created by
+/// [`IpcChannel::incoming`]. Iteration also ends once the channel has been
+/// closed and everything sent on it has been read, or after yielding the
+/// error if the kernel fails the read for any other reason.
+#[derive(Debug)]
+pub struct Incoming<'a> {
+    channel: &'a IpcChannel,
+    mode: IncomingMode,
+    finished: bool,
+}
+
+impl Iterator for Incoming<'_> {
+    type Item = Result<Message, ReadMessageError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+
+        let res = match self.mode {
+            IncomingMode::NonBlocking => self.channel.read().transpose()?,
+            IncomingMode::Blocking => self.channel.recv_blocking(),
+        };
+
+        match res {
+            Ok(message) => Some(Ok(message)),
+            Err(ReadMessageError::ChannelClosed) => {
+                self.finished = true;
+                None
+            }
+            Err(e) => {
+                self.finished = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
 pub struct NewMessage<'a> {
     channel: &'a mut IpcChannel,
     message: ChannelMessage,
@@ -85,14 +197,47 @@ pub struct NewMessage<'a> {
 }
 
 impl NewMessage<'_> {
-    pub fn send(self) -> Result<(), SendMessageError> {
-        self.channel.send(self.message, self.cursor)
+    pub fn id(&self) -> MessageId {
+        self.message.id
+    }
+
+    /// Send the message. If sending fails the message is cancelled when it's
+    /// dropped, as it would be if it had never been sent.
+    pub fn send(mut self) -> Result<(), SendMessageError> {
+        self.channel.send(self.message, self.cursor)?;
+        core::mem::forget(self);
+
+        Ok(())
+    }
+
+    /// Send the message with `tag` attached, see [`Message::tag`]. Failures
+    /// are handled as with [`NewMessage::send`].
+    pub fn send_tagged(self, tag: u64) -> Result<(), SendMessageError> {
+        match channel::send_tagged_message(self.channel.id, self.message.id, self.cursor, tag) {
+            SyscallResult::Ok(()) => {
+                core::mem::forget(self);
+                Ok(())
+            }
+            SyscallResult::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Send the message as a reply to the received message `to`. Failures are
+    /// handled as with [`NewMessage::send`].
+    pub fn send_reply(self, to: MessageId) -> Result<(), SendMessageError> {
+        match channel::send_reply(self.channel.id, self.message.id, self.cursor, to) {
+            SyscallResult::Ok(()) => {
+                core::mem::forget(self);
+                Ok(())
+            }
+            SyscallResult::Err(e) => Err(e.into()),
+        }
     }
 
     pub fn write(&mut self, buffer: &[u8]) {
-        assert!(self.cursor + buffer.len() < self.message.len);
+        assert!(self.cursor + buffer.len() < self.message.capacity);
         let slice = unsafe {
-            core::slice::from_raw_parts_mut(self.message.ptr.add(self.cursor), self.message.len - self.cursor)
+            core::slice::from_raw_parts_mut(self.message.ptr.add(self.cursor), self.message.capacity - self.cursor)
         };
         slice[..buffer.len()].copy_from_slice(buffer);
 
@@ -100,15 +245,229 @@ impl NewMessage<'_> {
     }
 
     pub fn as_bytes(&self) -> &[u8] {
-        unsafe { core::slice::from_raw_parts(self.message.ptr, self.message.len) }
+        unsafe { core::slice::from_raw_parts(self.message.ptr, self.message.capacity) }
+    }
+}
+
+impl core::ops::Drop for NewMessage<'_> {
+    fn drop(&mut self) {
+        let _ = channel::cancel_message(self.channel.id, self.message.id);
     }
 }
 
 #[derive(Debug, Clone, Copy)]
 pub enum OpenChannelError {
     InvalidTask,
+    TaskDead,
     Rejected,
 }
 
 #[derive(Debug)]
-pub enum SendMessageError {}
+pub enum SendMessageError {
+    /// The other end of the channel has gone away, so nothing more can be
+    /// sent on it
+    ChannelClosed,
+    /// The other end of the channel has as many messages waiting to be
+    /// retired as it's allowed
+    ChannelFull,
+    Kernel(KError),
+}
+
+impl From<KError> for SendMessageError {
+    fn from(e: KError) -> Self {
+        match e {
+            KError::ChannelClosed => Self::ChannelClosed,
+            KError::ChannelFull => Self::ChannelFull,
+            e => Self::Kernel(e),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ReadMessageError {
+    /// The other end of the channel has gone away, so nothing more will
+    /// arrive on it
+    ChannelClosed,
+    Kernel(KError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn write_message(channel: &mut IpcChannel, bytes: &[u8]) -> NewMessage<'_> {
+        let mut message = channel.new_message(bytes.len() + 1).unwrap();
+        message.write(bytes);
+        message
+    }
+
+    #[test]
+    fn recv_blocking_waits_in_the_kernel() {
+        let (a, b) = mock::channel_pair(4);
+        let b = IpcChannel::new(b);
+
+        // Nothing has been sent yet, so the receiver blocks until the sender
+        // gets to run
+        mock::when_blocked(move || write_message(&mut IpcChannel::new(a), b"ping").send().unwrap());
+
+        let message = b.recv_blocking().unwrap();
+        assert_eq!(&message.as_bytes()[..4], b"ping");
+        assert_eq!(mock::times_blocked(), 1);
+
+        // Anything already waiting is returned without blocking again
+        drop(message);
+        write_message(&mut IpcChannel::new(a), b"pong").send().unwrap();
+        assert_eq!(&b.recv_blocking().unwrap().as_bytes()[..4], b"pong");
+        assert_eq!(mock::times_blocked(), 1);
+    }
+
+    #[test]
+    fn non_blocking_incoming_drains_the_channel() {
+        let (a_id, b) = mock::channel_pair(4);
+        let mut a = IpcChannel::new(a_id);
+        let b = IpcChannel::new(b);
+
+        for byte in 1..=3 {
+            write_message(&mut a, &[byte]).send().unwrap();
+        }
+
+        let received: Vec<u8> = b.incoming(IncomingMode::NonBlocking).map(|m| m.unwrap().as_bytes()[0]).collect();
+        assert_eq!(received, [1, 2, 3]);
+        assert_eq!(mock::unretired(b.id), 0);
+        assert_eq!(mock::times_blocked(), 0);
+
+        // Closing the other end after it's been drained also ends iteration
+        mock::close(a_id);
+        assert!(b.incoming(IncomingMode::Blocking).next().is_none());
+    }
+
+    #[test]
+    fn dropped_incoming_leaves_the_rest() {
+        let (a, b) = mock::channel_pair(4);
+        let mut a = IpcChannel::new(a);
+        let b = IpcChannel::new(b);
+
+        for byte in 1..=3 {
+            write_message(&mut a, &[byte]).send().unwrap();
+        }
+
+        let first = b.incoming(IncomingMode::NonBlocking).next().unwrap().unwrap();
+        assert_eq!(first.as_bytes(), [1]);
+        drop(first);
+        assert_eq!(mock::unretired(b.id), 2);
+
+        let rest: Vec<u8> = b.incoming(IncomingMode::NonBlocking).map(|m| m.unwrap().as_bytes()[0]).collect();
+        assert_eq!(rest, [2, 3]);
+    }
+
+    #[test]
+    fn incoming_yields_kernel_errors() {
+        let (a, _) = mock::channel_pair(4);
+        let a = IpcChannel::new(a);
+        mock::close(a.id);
+
+        let mut incoming = a.incoming(IncomingMode::NonBlocking);
+        assert!(matches!(incoming.next(), Some(Err(ReadMessageError::Kernel(KError::InvalidArgument(0))))));
+        assert!(incoming.next().is_none());
+    }
+
+    #[test]
+    fn replies_match_overlapping_requests() {
+        let (a, b) = mock::channel_pair(4);
+        let mut client = IpcChannel::new(a);
+        let mut server = IpcChannel::new(b);
+
+        let first = write_message(&mut client, b"first");
+        let first_id = first.id();
+        first.send().unwrap();
+        let second = write_message(&mut client, b"second");
+        let second_id = second.id();
+        second.send().unwrap();
+
+        // Answer the requests out of order, replying with the request's length
+        let requests: Vec<(MessageId, usize)> = server
+            .incoming(IncomingMode::NonBlocking)
+            .map(|m| m.unwrap())
+            .map(|m| (m.id(), m.as_bytes().len()))
+            .collect();
+        for &(id, len) in requests.iter().rev() {
+            write_message(&mut server, &[len as u8]).send_reply(id).unwrap();
+        }
+
+        let mut replies: Vec<(Option<MessageId>, u8)> = client
+            .incoming(IncomingMode::NonBlocking)
+            .map(|m| m.unwrap())
+            .map(|m| (m.reply_to(), m.as_bytes()[0]))
+            .collect();
+        replies.sort();
+        assert_eq!(replies, [(Some(first_id), 5), (Some(second_id), 6)]);
+    }
+
+    #[test]
+    fn failed_reply_is_cancelled() {
+        let (a, b) = mock::channel_pair(1);
+        let mut client = IpcChannel::new(a);
+        let mut server = IpcChannel::new(b);
+
+        write_message(&mut client, b"request").send().unwrap();
+        let request = server.read().unwrap().unwrap();
+        let request_id = request.id();
+
+        // The client hasn't retired its earlier reply yet, so there's no room
+        write_message(&mut server, b"1").send_reply(request_id).unwrap();
+        let reply = write_message(&mut server, b"2");
+        let reply_id = reply.id();
+        assert!(matches!(reply.send_reply(request_id), Err(SendMessageError::ChannelFull)));
+        assert!(matches!(channel::cancel_message(server.id, reply_id), SyscallResult::Err(_)));
+
+        mock::close(a);
+        let reply = write_message(&mut server, b"3");
+        assert!(matches!(reply.send_reply(request_id), Err(SendMessageError::ChannelClosed)));
+    }
+
+    #[test]
+    fn tag_reaches_receiver() {
+        let (a, b) = mock::channel_pair(4);
+        let mut a = IpcChannel::new(a);
+        let b = IpcChannel::new(b);
+
+        write_message(&mut a, b"tagged").send_tagged(0xDEAD_BEEF_0000_0001).unwrap();
+        write_message(&mut a, b"untagged").send().unwrap();
+
+        let tagged = b.read().unwrap().unwrap();
+        assert_eq!(tagged.tag(), 0xDEAD_BEEF_0000_0001);
+        assert_eq!(tagged.as_bytes(), b"tagged");
+        drop(tagged);
+        assert_eq!(b.read().unwrap().unwrap().tag(), 0);
+    }
+
+    #[test]
+    fn failed_send_is_cancelled() {
+        let (a, b) = mock::channel_pair(1);
+        let mut a = IpcChannel::new(a);
+
+        write_message(&mut a, b"1").send().unwrap();
+
+        let message = write_message(&mut a, b"2");
+        let id = message.id();
+        assert!(matches!(message.send(), Err(SendMessageError::ChannelFull)));
+        assert!(matches!(channel::cancel_message(a.id, id), SyscallResult::Err(_)));
+
+        let message = write_message(&mut a, b"3");
+        assert!(matches!(message.send_tagged(1), Err(SendMessageError::ChannelFull)));
+
+        mock::close(b);
+        assert!(matches!(write_message(&mut a, b"4").send(), Err(SendMessageError::ChannelClosed)));
+    }
+
+    #[test]
+    fn recv_blocking_wakes_when_channel_closes() {
+        let (a, b) = mock::channel_pair(4);
+        let b = IpcChannel::new(b);
+
+        mock::when_blocked(move || mock::close(a));
+
+        assert!(matches!(b.recv_blocking(), Err(ReadMessageError::ChannelClosed)));
+    }
+}
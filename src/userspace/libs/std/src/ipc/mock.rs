@@ -0,0 +1,262 @@
+// SPDX-License-Identifier: MPL-2.0
+// SPDX-FileCopyrightText: 2021 The vanadinite developers
+//
+// This Source Code Form is subject to the terms of the Mozilla Public License,
+// v. 2.0. If a copy of the MPL was not distributed with this file, You can
+// obtain one at https://mozilla.org/MPL/2.0/.
+
+//! In-memory stand-ins for the channel syscalls used by [`super`], so the IPC
+//! types can be tested without a kernel. Each function behaves like the
+//! [`librust::syscalls::channel`] function of the same name, with every
+//! channel living in the calling thread.
+
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    vec,
+    vec::Vec,
+};
+use core::cell::RefCell;
+use librust::{
+    error::KError,
+    message::SyscallResult,
+    syscalls::channel::{ChannelId, ChannelMessage, MessageAccess, MessageId},
+    task::Tid,
+};
+
+struct Received {
+    data: Vec<u8>,
+    len: usize,
+    reply_to: Option<MessageId>,
+    sequence: usize,
+    tag: u64,
+}
+
+struct Channel {
+    /// The other end of the channel, or `None` once it's been closed
+    peer: Option<ChannelId>,
+    /// The most messages the other end can have waiting to be retired before
+    /// sending to it fails with [`KError::ChannelFull`]
+    limit: usize,
+    sent: usize,
+    outgoing: BTreeMap<MessageId, Vec<u8>>,
+    incoming: BTreeMap<MessageId, Received>,
+}
+
+#[derive(Default)]
+struct State {
+    channels: BTreeMap<ChannelId, Channel>,
+    next_id: usize,
+    /// Run in order each time [`receive_any`] would block, standing in for
+    /// whatever another task does in the meantime
+    on_block: VecDeque<Box<dyn FnOnce()>>,
+    times_blocked: usize,
+}
+
+impl State {
+    fn next_id(&mut self) -> usize {
+        self.next_id += 1;
+        self.next_id
+    }
+}
+
+host_std::thread_local! {
+    static STATE: RefCell<State> = RefCell::new(State::default());
+}
+
+fn with_state<T>(f: impl FnOnce(&mut State) -> T) -> T {
+    STATE.with(|state| f(&mut state.borrow_mut()))
+}
+
+/// Connect two new channels, with at most `limit` messages in flight towards
+/// each end
+pub fn channel_pair(limit: usize) -> (ChannelId, ChannelId) {
+    with_state(|state| {
+        let (a, b) = (ChannelId::new(state.next_id()), ChannelId::new(state.next_id()));
+        let new =
+            |peer| Channel { peer: Some(peer), limit, sent: 0, outgoing: BTreeMap::new(), incoming: BTreeMap::new() };
+
+        state.channels.insert(a, new(b));
+        state.channels.insert(b, new(a));
+
+        (a, b)
+    })
+}
+
+/// Close `channel`, as if its task had exited
+pub fn close(channel: ChannelId) {
+    with_state(|state| {
+        if let Some(peer) = state.channels.remove(&channel).and_then(|channel| channel.peer) {
+            state.channels.get_mut(&peer).unwrap().peer = None;
+        }
+    })
+}
+
+/// Run `f` the next time the task would block waiting for a message
+pub fn when_blocked(f: impl FnOnce() + 'static) {
+    with_state(|state| state.on_block.push_back(Box::new(f)));
+}
+
+/// How many times the task has blocked waiting for a message
+pub fn times_blocked() -> usize {
+    with_state(|state| state.times_blocked)
+}
+
+/// The number of messages received on `channel` which haven't been retired
+pub fn unretired(channel: ChannelId) -> usize {
+    with_state(|state| state.channels[&channel].incoming.len())
+}
+
+pub fn request_channel(_: Tid) -> SyscallResult<(), KError> {
+    SyscallResult::Err(KError::InvalidRecipient)
+}
+
+pub fn create_message(channel: ChannelId, size: usize) -> SyscallResult<ChannelMessage, KError> {
+    with_state(|state| {
+        let id = MessageId::new(state.next_id());
+        let channel = match state.channels.get_mut(&channel) {
+            Some(channel) => channel,
+            None => return SyscallResult::Err(KError::InvalidArgument(0)),
+        };
+
+        let capacity = (size + 4095) & !4095;
+        let mut data = vec![0; capacity];
+        let ptr = data.as_mut_ptr();
+        channel.outgoing.insert(id, data);
+
+        SyscallResult::Ok(ChannelMessage {
+            id,
+            ptr,
+            len: size,
+            capacity,
+            access: MessageAccess::ReadWrite,
+            reply_to: None,
+            sequence: 0,
+            tag: 0,
+            sender: None,
+        })
+    })
+}
+
+fn send(
+    channel: ChannelId,
+    message: MessageId,
+    len: usize,
+    reply_to: Option<MessageId>,
+    tag: u64,
+) -> SyscallResult<(), KError> {
+    with_state(|state| {
+        let sender = match state.channels.get_mut(&channel) {
+            Some(sender) if sender.outgoing.contains_key(&message) => sender,
+            _ => return SyscallResult::Err(KError::InvalidArgument(1)),
+        };
+
+        let peer = match sender.peer {
+            Some(peer) => peer,
+            None => return SyscallResult::Err(KError::ChannelClosed),
+        };
+
+        if state.channels[&peer].incoming.len() >= state.channels[&peer].limit {
+            return SyscallResult::Err(KError::ChannelFull);
+        }
+
+        let sender = state.channels.get_mut(&channel).unwrap();
+        let data = sender.outgoing.remove(&message).unwrap();
+        sender.sent += 1;
+        let sequence = sender.sent;
+
+        let received = Received { data, len, reply_to, sequence, tag };
+        state.channels.get_mut(&peer).unwrap().incoming.insert(message, received);
+
+        SyscallResult::Ok(())
+    })
+}
+
+pub fn send_message(channel: ChannelId, message: MessageId, message_len: usize) -> SyscallResult<(), KError> {
+    send(channel, message, message_len, None, 0)
+}
+
+pub fn send_tagged_message(
+    channel: ChannelId,
+    message: MessageId,
+    message_len: usize,
+    tag: u64,
+) -> SyscallResult<(), KError> {
+    send(channel, message, message_len, None, tag)
+}
+
+pub fn send_reply(
+    channel: ChannelId,
+    message: MessageId,
+    message_len: usize,
+    reply_to: MessageId,
+) -> SyscallResult<(), KError> {
+    send(channel, message, message_len, Some(reply_to), 0)
+}
+
+fn first_message(state: &mut State, channel: ChannelId) -> Option<ChannelMessage> {
+    let (&id, message) = state.channels.get_mut(&channel)?.incoming.iter_mut().next()?;
+
+    Some(ChannelMessage {
+        id,
+        ptr: message.data.as_mut_ptr(),
+        len: message.len,
+        capacity: message.data.len(),
+        access: MessageAccess::ReadWrite,
+        reply_to: message.reply_to,
+        sequence: message.sequence,
+        tag: message.tag,
+        sender: None,
+    })
+}
+
+pub fn read_message(channel: ChannelId) -> SyscallResult<Option<ChannelMessage>, KError> {
+    with_state(|state| {
+        let closed = match state.channels.get(&channel) {
+            Some(channel) => channel.peer.is_none() && channel.incoming.is_empty(),
+            None => return SyscallResult::Err(KError::InvalidArgument(0)),
+        };
+
+        match closed {
+            true => SyscallResult::Err(KError::ChannelClosed),
+            false => SyscallResult::Ok(first_message(state, channel)),
+        }
+    })
+}
+
+pub fn receive_any() -> SyscallResult<(ChannelId, Option<ChannelMessage>), KError> {
+    loop {
+        let on_block = with_state(|state| {
+            let ready = state.channels.iter().find(|(_, channel)| !channel.incoming.is_empty());
+            if let Some((&id, _)) = ready {
+                return Ok((id, first_message(state, id)));
+            }
+
+            if let Some((&id, _)) = state.channels.iter().find(|(_, channel)| channel.peer.is_none()) {
+                return Ok((id, None));
+            }
+
+            state.times_blocked += 1;
+            Err(state.on_block.pop_front().expect("blocked with nothing left to wake the task"))
+        });
+
+        match on_block {
+            Ok(received) => return SyscallResult::Ok(received),
+            Err(f) => f(),
+        }
+    }
+}
+
+pub fn retire_message(channel: ChannelId, message: MessageId) -> SyscallResult<(), KError> {
+    with_state(|state| match state.channels.get_mut(&channel).and_then(|channel| channel.incoming.remove(&message)) {
+        Some(_) => SyscallResult::Ok(()),
+        None => SyscallResult::Err(KError::InvalidArgument(1)),
+    })
+}
+
+pub fn cancel_message(channel: ChannelId, message: MessageId) -> SyscallResult<(), KError> {
+    with_state(|state| match state.channels.get_mut(&channel).and_then(|channel| channel.outgoing.remove(&message)) {
+        Some(_) => SyscallResult::Ok(()),
+        None => SyscallResult::Err(KError::InvalidArgument(1)),
+    })
+}
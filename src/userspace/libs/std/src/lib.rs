@@ -11,6 +11,10 @@
 
 extern crate alloc;
 
+// Tests run on the host, which the sysroot's `std` is needed for
+#[cfg(test)]
+extern crate std as host_std;
+
 #[cfg(feature = "rt0")]
 extern crate rt0;
 
@@ -54,12 +58,14 @@ pub fn _print(args: core::fmt::Arguments) {
     let _ = io::Stdout.write_fmt(args);
 }
 
+#[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
     println!("PANIC: {}", info);
-    librust::syscalls::exit()
+    librust::syscalls::exit_with(librust::task::ExitReason::Panicked)
 }
 
+#[cfg(not(test))]
 #[alloc_error_handler]
 fn alloc_error(layout: alloc::alloc::Layout) -> ! {
     panic!("Error allocating memory with layout: {:?}", layout)